@@ -0,0 +1,106 @@
+// Records high level events of assuan requests sent to the extra socket.
+//
+// Only command names and keygrips are recorded. Data lines and arguments of other commands
+// may carry secret material, so they are never written.
+
+use log::error;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// Assuan limits a line to 1000 bytes, longer lines are not valid commands.
+const MAX_LINE_LEN: usize = 1000;
+
+/// An append-only audit log of requests forwarded to the extra socket.
+pub struct Auditor {
+    file: parking_lot::Mutex<File>,
+}
+
+impl Auditor {
+    /// Opens the log at `path` for appending, it's created if not exists.
+    pub fn open(path: &Path) -> io::Result<Auditor> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Auditor {
+            file: parking_lot::Mutex::new(file),
+        })
+    }
+
+    /// Starts recording requests of connection `id`.
+    pub(crate) fn session(self: &Arc<Self>, id: u64) -> AuditSession {
+        AuditSession {
+            auditor: self.clone(),
+            id,
+            line: Vec::with_capacity(MAX_LINE_LEN),
+            overflow: false,
+            keygrip: None,
+        }
+    }
+
+    fn record(&self, id: u64, event: &str) {
+        let now = humantime::format_rfc3339_seconds(SystemTime::now());
+        let mut file = self.file.lock();
+        if let Err(e) = writeln!(file, "{} conn={} {}", now, id, event) {
+            error!("failed to write audit log: {:?}", e);
+        }
+    }
+}
+
+/// Parses the requests of one connection.
+pub(crate) struct AuditSession {
+    auditor: Arc<Auditor>,
+    id: u64,
+    line: Vec<u8>,
+    overflow: bool,
+    keygrip: Option<String>,
+}
+
+impl AuditSession {
+    /// Feeds the bytes sent from client to agent.
+    pub fn feed(&mut self, data: &[u8]) {
+        for b in data {
+            if *b == b'\n' {
+                if !self.overflow {
+                    self.process_line();
+                }
+                for b in &mut self.line {
+                    *b = 0;
+                }
+                self.line.clear();
+                self.overflow = false;
+            } else if self.line.len() < MAX_LINE_LEN {
+                self.line.push(*b);
+            } else {
+                self.overflow = true;
+            }
+        }
+    }
+
+    fn process_line(&mut self) {
+        let line = String::from_utf8_lossy(&self.line);
+        let line = line.trim_end_matches('\r');
+        let (cmd, args) = match line.find(' ') {
+            Some(pos) => (&line[..pos], line[pos + 1..].trim()),
+            None => (line, ""),
+        };
+        let cmd = cmd.to_ascii_uppercase();
+        let keygrip = args.split(' ').next().unwrap_or_default();
+        let event = match cmd.as_str() {
+            // Data lines may carry secret material.
+            "" | "D" | "END" => return,
+            _ if cmd.starts_with('#') => return,
+            "SETKEY" | "SIGKEY" => {
+                self.keygrip = Some(keygrip.to_owned());
+                return;
+            }
+            "PKDECRYPT" | "PKSIGN" => match &self.keygrip {
+                Some(k) => format!("{} on keygrip {}", cmd, k),
+                None => cmd,
+            },
+            "HAVEKEY" | "KEYINFO" | "READKEY" => format!("{} {}", cmd, keygrip),
+            _ => cmd,
+        };
+        self.auditor.record(self.id, &event);
+    }
+}
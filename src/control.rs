@@ -0,0 +1,235 @@
+// A control channel that reconfigures listeners at runtime.
+//
+// Every line received is a command, and is answered by a line starting with "OK" or "ERR".
+// - `add <ssh|extra|dirmngr> <ADDRESS> [GNUPGHOME]` starts forwarding requests from
+//   `ADDRESS`, to the agent of `GNUPGHOME` if specified. GNUPGHOME is only allowed for extra
+//   socket.
+// - `remove <ADDRESS>` stops accepting new connections from `ADDRESS`, accepted connections
+//   are left to finish.
+// - `list` replies all active listeners as `<ssh|extra|dirmngr> <ADDRESS> [GNUPGHOME]`, one
+//   per line, before "OK".
+// - `--status-json` replies health of the bridge as a single line of JSON before "OK", with fields
+//   `version`, `uptime_secs`, `sockets`, `active_connections`, `total_connections`,
+//   `bytes_in`, `bytes_out` and `agent_reachable`, which is null until a connection to extra
+//   socket is forwarded.
+// - `pause` closes new connections instead of forwarding them, `resume` forwards them again.
+// - `upgrade` hands off all listeners to a new process of current executable, only allowed
+//   with `--reexec-on-upgrade`.
+
+use crate::util::{bind, is_pipe_addr, other_error, BindOptions, Listener, SplitStream};
+use crate::{Bridge, SocketType};
+use log::{debug, error};
+use serde::Serialize;
+use std::io;
+use std::path::PathBuf;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::windows::named_pipe::ClientOptions;
+
+/// Splits the first word from `s`, returns the word and the rest.
+fn next_word(s: &str) -> (&str, &str) {
+    let s = s.trim_start();
+    match s.find(char::is_whitespace) {
+        Some(pos) => (&s[..pos], s[pos..].trim()),
+        None => (s, ""),
+    }
+}
+
+/// Reply of `--status-json`.
+#[derive(Serialize)]
+struct Status {
+    version: &'static str,
+    uptime_secs: u64,
+    sockets: Vec<SocketStatus>,
+    active_connections: usize,
+    total_connections: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+    agent_reachable: Option<bool>,
+}
+
+#[derive(Serialize)]
+struct SocketStatus {
+    #[serde(rename = "type")]
+    ty: &'static str,
+    address: String,
+    gnupghome: Option<String>,
+}
+
+fn status(bridge: &Bridge) -> io::Result<String> {
+    let stats = bridge.stats();
+    let sockets = bridge
+        .listeners()
+        .into_iter()
+        .map(|l| SocketStatus {
+            ty: l.ty.short_name(),
+            address: l.addr,
+            gnupghome: l.gnupghome.map(|h| h.to_string_lossy().into_owned()),
+        })
+        .collect();
+    let status = Status {
+        version: env!("CARGO_PKG_VERSION"),
+        uptime_secs: stats.uptime.as_secs(),
+        sockets,
+        active_connections: stats.active,
+        total_connections: stats.connections,
+        bytes_in: stats.received,
+        bytes_out: stats.replied,
+        agent_reachable: stats.agent_reachable,
+    };
+    let mut out = serde_json::to_string(&status).map_err(|e| other_error(e.to_string()))?;
+    out.push('\n');
+    Ok(out)
+}
+
+fn invalid_command(command: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("unknown command {:?}", command),
+    )
+}
+
+async fn execute(bridge: &Bridge, command: &str) -> io::Result<String> {
+    let (name, args) = next_word(command);
+    match name {
+        "add" => {
+            let (ty, rest) = next_word(args);
+            let (addr, home) = next_word(rest);
+            if addr.is_empty() {
+                return Err(invalid_command(command));
+            }
+            let ty = match SocketType::from_short_name(ty) {
+                Some(ty) => ty,
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("unknown socket type {}", ty),
+                    ))
+                }
+            };
+            // GNUPGHOME is the rest of the line, so it can contain spaces.
+            let home = Some(home).filter(|h| !h.is_empty()).map(PathBuf::from);
+            bridge.add_listener(ty, addr.to_owned(), home).await?;
+            Ok(String::new())
+        }
+        "remove" if !args.is_empty() && next_word(args).1.is_empty() => {
+            bridge.remove_listener(args)?;
+            Ok(String::new())
+        }
+        "list" if args.is_empty() => {
+            let mut out = String::new();
+            for l in bridge.listeners() {
+                out.push_str(l.ty.short_name());
+                out.push(' ');
+                out.push_str(&l.addr);
+                if let Some(home) = &l.gnupghome {
+                    out.push(' ');
+                    out.push_str(&home.to_string_lossy());
+                }
+                out.push('\n');
+            }
+            Ok(out)
+        }
+        "--status-json" if args.is_empty() => status(bridge),
+        "pause" if args.is_empty() => {
+            bridge.pause();
+            Ok(String::new())
+        }
+        "resume" if args.is_empty() => {
+            bridge.resume();
+            Ok(String::new())
+        }
+        "upgrade" if args.is_empty() => {
+            bridge.upgrade().await?;
+            Ok(String::new())
+        }
+        _ => Err(invalid_command(command)),
+    }
+}
+
+async fn handle(bridge: &Bridge, mut conn: impl SplitStream) -> io::Result<()> {
+    let (reader, mut writer) = conn.split_rw();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        debug!("control command {:?}", line);
+        let reply = match execute(bridge, line.trim()).await {
+            Ok(out) => format!("{}OK\n", out),
+            Err(e) => format!("ERR {}\n", e),
+        };
+        writer.write_all(reply.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// Accepts commands from `addr` to add or remove listeners of `bridge`.
+///
+/// `addr` should be a Named Pipe. Commands are not authenticated, so the pipe only grants
+/// current user access, and clients running as other users are rejected.
+pub async fn serve_control(bridge: Bridge, addr: String) -> io::Result<()> {
+    if !is_pipe_addr(&addr) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("control address {} should be a named pipe", addr),
+        ));
+    }
+    // Control pipe should never be shared with others.
+    let opts = BindOptions {
+        pipe_owner_only: true,
+        pipe_require_same_user: true,
+        ..BindOptions::default()
+    };
+    let mut listener = bind(&addr, &opts).await?;
+    bridge.set_control(&addr, &listener);
+    loop {
+        let conn = listener.accept().await?;
+        let bridge = bridge.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(&bridge, conn).await {
+                error!("failed to handle control connection: {:?}", e);
+            }
+        });
+    }
+}
+
+/// Gets the reply of `--status-json` from the control pipe `addr` of a running bridge.
+pub async fn query_status(addr: &str) -> io::Result<String> {
+    let mut conn = ClientOptions::new().open(addr)?;
+    conn.write_all(b"--status-json\n").await?;
+    let mut lines = BufReader::new(conn).lines();
+    let mut out = String::new();
+    while let Some(line) = lines.next_line().await? {
+        if line == "OK" {
+            return Ok(out);
+        }
+        if let Some(e) = line.strip_prefix("ERR ") {
+            return Err(other_error(e.to_owned()));
+        }
+        out.push_str(&line);
+        out.push('\n');
+    }
+    Err(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "control pipe is closed before replying",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ActiveConnection, BridgeOptions};
+
+    #[tokio::test]
+    async fn test_status_json() {
+        let bridge = Bridge::new(BridgeOptions::default());
+        let _active = ActiveConnection::new(&bridge.core);
+        drop(ActiveConnection::new(&bridge.core));
+        let out = execute(&bridge, "--status-json").await.unwrap();
+        assert!(out.ends_with('\n') && !out.trim_end().contains('\n'));
+        let status: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(status["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(status["sockets"], serde_json::json!([]));
+        assert_eq!(status["active_connections"], 1);
+        assert_eq!(status["total_connections"], 2);
+        assert_eq!(status["agent_reachable"], serde_json::Value::Null);
+        assert!(execute(&bridge, "--status-json now").await.is_err());
+    }
+}
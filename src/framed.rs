@@ -0,0 +1,258 @@
+// Length delimited frames with checksum, which detect corrupted streams between two peers.
+//
+// Every frame is a 4 bytes big endian payload length, followed by 4 bytes big endian CRC-32
+// (IEEE) of the payload, then the payload. Payload can't be empty or longer than
+// `MAX_FRAME_LEN`. Closing the stream ends the data, there is no special end frame.
+//
+// Clients may be required to send a correlation id as the first frame, which is logged along
+// with the connection id instead of being forwarded, so a request can be traced across hops.
+
+use crate::util::{Peer, PinAsyncRead, PinAsyncWrite, SplitStream};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+
+/// Maximum payload length of a frame.
+pub const MAX_FRAME_LEN: usize = 65536;
+const HEADER_LEN: usize = 8;
+/// Maximum length of a correlation id.
+pub const MAX_CORRELATION_ID_LEN: usize = 64;
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (!(crc & 1)).wrapping_add(1));
+        }
+    }
+    !crc
+}
+
+fn corrupted(details: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, details)
+}
+
+/// Reads the correlation id from the first frame of `stream`, it should be printable ASCII.
+pub async fn read_correlation_id(stream: &mut impl SplitStream) -> io::Result<String> {
+    let (mut reader, _) = stream.split_rw();
+    let mut header = [0; HEADER_LEN];
+    reader.read_exact(&mut header).await?;
+    let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    if len == 0 || len > MAX_CORRELATION_ID_LEN {
+        return Err(corrupted(format!("invalid correlation id length {}", len)));
+    }
+    let mut id = vec![0; len];
+    reader.read_exact(&mut id).await?;
+    if crc32(&id) != u32::from_be_bytes([header[4], header[5], header[6], header[7]]) {
+        return Err(corrupted("frame checksum mismatch".to_string()));
+    }
+    // Anything else may forge log lines.
+    if !id.iter().all(u8::is_ascii_graphic) {
+        return Err(corrupted("correlation id is not printable".to_string()));
+    }
+    Ok(String::from_utf8(id).unwrap())
+}
+
+/// Wraps a stream whose both directions are framed.
+pub struct Framed<T>(pub T);
+
+impl<T: SplitStream> SplitStream for Framed<T> {
+    fn split_rw(&mut self) -> (PinAsyncRead<'_>, PinAsyncWrite<'_>) {
+        let (reader, writer) = self.0.split_rw();
+        (
+            Box::pin(FrameReader {
+                inner: reader,
+                state: ReadState::Header,
+                header: [0; HEADER_LEN],
+                payload: Vec::new(),
+                pos: 0,
+            }),
+            Box::pin(FrameWriter {
+                inner: writer,
+                pending: Vec::new(),
+                written: 0,
+                consumed: 0,
+            }),
+        )
+    }
+
+    fn peer(&self) -> Peer {
+        self.0.peer()
+    }
+}
+
+enum ReadState {
+    Header,
+    Payload,
+    /// Payload is verified and being handed out.
+    Ready,
+}
+
+struct FrameReader<'a> {
+    inner: PinAsyncRead<'a>,
+    state: ReadState,
+    header: [u8; HEADER_LEN],
+    payload: Vec<u8>,
+    /// Bytes filled into header or payload, or bytes of payload handed out.
+    pos: usize,
+}
+
+/// Reads into `buf[*filled..]` once, returns false on EOF.
+fn poll_fill(
+    inner: &mut PinAsyncRead<'_>,
+    cx: &mut Context<'_>,
+    buf: &mut [u8],
+    filled: &mut usize,
+) -> Poll<io::Result<bool>> {
+    let mut read_buf = ReadBuf::new(&mut buf[*filled..]);
+    futures::ready!(inner.as_mut().poll_read(cx, &mut read_buf))?;
+    let n = read_buf.filled().len();
+    *filled += n;
+    Poll::Ready(Ok(n > 0))
+}
+
+impl<'a> AsyncRead for FrameReader<'a> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            match this.state {
+                ReadState::Header => {
+                    let more = futures::ready!(poll_fill(
+                        &mut this.inner,
+                        cx,
+                        &mut this.header,
+                        &mut this.pos
+                    ))?;
+                    if !more {
+                        if this.pos == 0 {
+                            return Poll::Ready(Ok(()));
+                        }
+                        return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                    }
+                    if this.pos < HEADER_LEN {
+                        continue;
+                    }
+                    let h = &this.header;
+                    let len = u32::from_be_bytes([h[0], h[1], h[2], h[3]]) as usize;
+                    if len == 0 || len > MAX_FRAME_LEN {
+                        return Poll::Ready(Err(corrupted(format!(
+                            "invalid frame length {}",
+                            len
+                        ))));
+                    }
+                    this.payload.resize(len, 0);
+                    this.pos = 0;
+                    this.state = ReadState::Payload;
+                }
+                ReadState::Payload => {
+                    let more = futures::ready!(poll_fill(
+                        &mut this.inner,
+                        cx,
+                        &mut this.payload,
+                        &mut this.pos
+                    ))?;
+                    if !more {
+                        return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                    }
+                    if this.pos < this.payload.len() {
+                        continue;
+                    }
+                    let h = &this.header;
+                    if crc32(&this.payload) != u32::from_be_bytes([h[4], h[5], h[6], h[7]]) {
+                        return Poll::Ready(Err(corrupted("frame checksum mismatch".to_string())));
+                    }
+                    this.pos = 0;
+                    this.state = ReadState::Ready;
+                }
+                ReadState::Ready => {
+                    let n = (this.payload.len() - this.pos).min(buf.remaining());
+                    buf.put_slice(&this.payload[this.pos..this.pos + n]);
+                    this.pos += n;
+                    if this.pos == this.payload.len() {
+                        for b in &mut this.payload {
+                            *b = 0;
+                        }
+                        this.payload.clear();
+                        this.pos = 0;
+                        this.state = ReadState::Header;
+                    }
+                    return Poll::Ready(Ok(()));
+                }
+            }
+        }
+    }
+}
+
+struct FrameWriter<'a> {
+    inner: PinAsyncWrite<'a>,
+    /// Encoded frame that has not been written completely.
+    pending: Vec<u8>,
+    written: usize,
+    /// Bytes of caller's buffer encoded in `pending`.
+    consumed: usize,
+}
+
+impl<'a> FrameWriter<'a> {
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.pending.len() {
+            let n = futures::ready!(self
+                .inner
+                .as_mut()
+                .poll_write(cx, &self.pending[self.written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.written += n;
+        }
+        for b in &mut self.pending {
+            *b = 0;
+        }
+        self.pending.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<'a> AsyncWrite for FrameWriter<'a> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        // A pending frame is always encoded from the same buffer, as callers have to retry
+        // with the same data after `Pending`.
+        if this.pending.is_empty() {
+            let payload = &buf[..buf.len().min(MAX_FRAME_LEN)];
+            this.pending
+                .extend_from_slice(&(payload.len() as u32).to_be_bytes());
+            this.pending
+                .extend_from_slice(&crc32(payload).to_be_bytes());
+            this.pending.extend_from_slice(payload);
+            this.consumed = payload.len();
+        }
+        futures::ready!(this.poll_pending(cx))?;
+        Poll::Ready(Ok(this.consumed))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        futures::ready!(this.poll_pending(cx))?;
+        this.inner.as_mut().poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        futures::ready!(this.poll_pending(cx))?;
+        this.inner.as_mut().poll_shutdown(cx)
+    }
+}
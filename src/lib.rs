@@ -1,30 +1,112 @@
+mod audit;
+mod control;
+mod framed;
+mod log_file;
+mod pinentry;
+mod power;
+mod probe;
 mod ssh;
 mod util;
 
-pub use self::util::other_error;
-use crate::util::{Listener, NamedPipeServerListener, SplitStream};
-use log::{debug, error, trace};
-use std::path::Path;
+pub use self::audit::Auditor;
+pub use self::control::{query_status, serve_control};
+pub use self::log_file::FileLogger;
+pub use self::ssh::{set_guid_map_names, set_pageant_magic, Identity, PUTTY_IPC_MAGIC};
+pub use self::util::{
+    bind, expand_env_vars, load_inherited_listeners, other_error, parse_listen_addr,
+    set_max_message_log_bytes, set_trace_timing, AcceptFuture, AnyListener, BindOptions,
+    ListenKind, Listener, Peer, PinAsyncRead, PinAsyncWrite, SplitStream,
+    DEFAULT_MAX_MESSAGE_LOG_BYTES, DEFAULT_PIPE_QUEUE_DEPTH, INHERIT_ENV, UPGRADE_READY,
+};
+use crate::audit::AuditSession;
+use crate::framed::Framed;
+use crate::pinentry::PinentryWatch;
+use crate::util::{
+    is_pipe_addr, local_pipe_addr, loggable, set_inheritable, trace_timing,
+    unavailable_reparse_target, ScrubBuf, ScrubReader,
+};
+use futures::future::BoxFuture;
+use log::{debug, error, info, trace, warn};
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::os::windows::io::RawSocket;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
-use std::{error, io, mem, ptr, str};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use std::{env, error, fmt, io, mem, str};
 use tokio::fs::File;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use tokio::net::windows::named_pipe::ServerOptions;
-use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::windows::named_pipe::ClientOptions;
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
 use tokio::process::Command;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, Notify};
+use tokio::time;
 
 struct AgentMeta {
-    path: Option<String>,
-    args: Option<(u16, [u8; 16])>,
+    path: Option<PathBuf>,
+    /// Whether `path` is discovered instead of configured, only discovered path is refreshed.
+    discovered: bool,
+    target: Option<AgentTarget>,
+    /// Until when opening the agent fails fast, and the error that caused it.
+    down: Option<(time::Instant, io::ErrorKind, String)>,
 }
 
-#[derive(Clone, Copy)]
+/// An agent identified by its GNUPGHOME.
+struct Agent {
+    /// `SocketType::Extra` or `SocketType::Dirmngr`, the socket requests are forwarded to.
+    ty: SocketType,
+    /// `None` means using the home inherited from current process.
+    home: Option<PathBuf>,
+    meta: Mutex<AgentMeta>,
+    /// Whether a connection has been forwarded to the agent.
+    warmed_up: AtomicBool,
+    /// Agents tried in order when this one can't be opened.
+    fallbacks: Vec<Arc<Agent>>,
+    /// Index of the agent that was opened last time, 0 is this one and `i` is
+    /// `fallbacks[i - 1]`.
+    current: AtomicUsize,
+    /// Number of connections failed in a row.
+    failures: AtomicUsize,
+}
+
+impl Agent {
+    fn new(ty: SocketType, home: Option<PathBuf>, path: Option<PathBuf>) -> Agent {
+        Agent {
+            ty,
+            home,
+            meta: Mutex::new(AgentMeta {
+                path,
+                discovered: false,
+                target: None,
+                down: None,
+            }),
+            warmed_up: AtomicBool::new(false),
+            fallbacks: Vec::new(),
+            current: AtomicUsize::new(0),
+            failures: AtomicUsize::new(0),
+        }
+    }
+
+    fn backend(&self, i: usize) -> &Agent {
+        if i == 0 {
+            self
+        } else {
+            &self.fallbacks[i - 1]
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum SocketType {
     Ssh,
     Extra,
+    /// Socket of dirmngr, which does keyserver and WKD lookups for gpg.
+    Dirmngr,
 }
 
 impl SocketType {
@@ -32,41 +114,182 @@ impl SocketType {
         match self {
             SocketType::Ssh => "agent-ssh-socket",
             SocketType::Extra => "agent-extra-socket",
+            SocketType::Dirmngr => "dirmngr-socket",
+        }
+    }
+
+    /// The name used in command line and control commands.
+    pub fn short_name(&self) -> &'static str {
+        match self {
+            SocketType::Ssh => "ssh",
+            SocketType::Extra => "extra",
+            SocketType::Dirmngr => "dirmngr",
+        }
+    }
+
+    /// Parses the name returned by `short_name`.
+    pub fn from_short_name(name: &str) -> Option<SocketType> {
+        match name {
+            "ssh" => Some(SocketType::Ssh),
+            "extra" => Some(SocketType::Extra),
+            "dirmngr" => Some(SocketType::Dirmngr),
+            _ => None,
+        }
+    }
+}
+
+/// Environment variable that pins the path of gnupg extra socket.
+///
+/// It takes precedence over `gpgconf` discovery, but not explicitly configured path.
+pub const EXTRA_SOCKET_ENV: &str = "GPG_BRIDGE_EXTRA_SOCKET";
+
+#[cfg(test)]
+thread_local! {
+    /// Stands in for `EXTRA_SOCKET_ENV` in tests, as environment is shared by tests running
+    /// in parallel.
+    static PINNED_EXTRA_SOCKET: std::cell::RefCell<Option<OsString>> = Default::default();
+}
+
+fn pinned_extra_socket() -> Option<OsString> {
+    #[cfg(not(test))]
+    let path = env::var_os(EXTRA_SOCKET_ENV);
+    #[cfg(test)]
+    let path = PINNED_EXTRA_SOCKET.with(|p| p.borrow().clone());
+    path.filter(|p| !p.is_empty())
+}
+
+/// Creates a command of gnupg tools, which works on `home` if specified.
+fn gpg_command(program: impl AsRef<OsStr>, home: Option<&Path>) -> Command {
+    let mut cmd = Command::new(program);
+    if let Some(home) = home {
+        cmd.env("GNUPGHOME", home);
+    }
+    cmd
+}
+
+async fn load_gpg_socket_path(ty: SocketType, home: Option<&Path>) -> io::Result<PathBuf> {
+    // The environment variable is for the home inherited from current process.
+    if ty == SocketType::Extra && home.is_none() {
+        if let Some(path) = pinned_extra_socket() {
+            return Ok(PathBuf::from(path));
         }
     }
+    gpgconf_list_dir(ty.name(), home).await
 }
 
-async fn load_gpg_socket_path(ty: SocketType) -> io::Result<String> {
-    let output = Command::new("gpgconf")
+async fn gpgconf_list_dir(name: &str, home: Option<&Path>) -> io::Result<PathBuf> {
+    let output = gpg_command("gpgconf", home)
         .arg("--list-dir")
-        .arg(ty.name())
+        .arg(name)
         .output()
         .await?;
     if !output.status.success() {
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            format!(
-                "failed to load extra socket: {:?}",
-                String::from_utf8_lossy(&output.stderr)
-            ),
-        ));
+        return Err(other_error(format!(
+            "failed to load {}: {:?}",
+            name,
+            String::from_utf8_lossy(&output.stderr)
+        )));
+    }
+    // Path is kept as is, it may contain spaces or non-ASCII characters.
+    match decode_output(output.stdout) {
+        Ok(s) if s.trim().is_empty() => Err(other_error(format!("{} is not set", name))),
+        Ok(s) => Ok(PathBuf::from(s.trim())),
+        Err(e) => Err(e),
+    }
+}
+
+/// Decodes output of gnupg tools, which is UTF-16 LE on some locales.
+fn decode_output(output: Vec<u8>) -> io::Result<String> {
+    if let Some(utf16) = output.strip_prefix(&[0xff, 0xfe]) {
+        return decode_utf16(utf16);
+    }
+    // Text never contains NUL, while UTF-16 of ASCII has one in every other byte, which is
+    // valid UTF-8 as well.
+    if output.len().is_multiple_of(2) && output.contains(&0) {
+        return decode_utf16(&output);
+    }
+    match String::from_utf8(output) {
+        Ok(s) => Ok(s.trim_start_matches('\u{feff}').to_owned()),
+        // Without BOM, UTF-16 can only be guessed from invalid UTF-8.
+        Err(e) if e.as_bytes().len().is_multiple_of(2) => decode_utf16(e.as_bytes()),
+        Err(e) => Err(report_data_err(e)),
+    }
+}
+
+fn decode_utf16(data: &[u8]) -> io::Result<String> {
+    if !data.len().is_multiple_of(2) {
+        return Err(report_data_err("truncated UTF-16 output"));
+    }
+    let units: Vec<u16> = data
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect();
+    String::from_utf16(&units).map_err(report_data_err)
+}
+
+/// Command that starts gpg-agent if it's not up yet.
+#[derive(Clone, Debug)]
+pub struct AgentStartup {
+    pub program: PathBuf,
+    pub args: Vec<String>,
+}
+
+impl Default for AgentStartup {
+    fn default() -> AgentStartup {
+        AgentStartup {
+            program: PathBuf::from("gpg-connect-agent"),
+            args: vec!["/bye".to_owned()],
+        }
+    }
+}
+
+impl AgentStartup {
+    /// Command that starts dirmngr by `gpg-connect-agent --dirmngr /bye`, `program` is the
+    /// path to gpg-connect-agent.
+    pub fn dirmngr(program: PathBuf) -> AgentStartup {
+        AgentStartup {
+            program,
+            args: vec!["--dirmngr".to_owned(), "/bye".to_owned()],
+        }
     }
-    Ok(String::from_utf8(output.stdout).unwrap().trim().to_owned())
 }
 
 pub async fn ping_gpg_agent() -> io::Result<()> {
-    let output = Command::new("gpg-connect-agent")
-        .arg("/bye")
+    ping_gpg_agent_with(&AgentStartup::default(), None).await
+}
+
+/// Kills the daemon serving sockets of `ty` in `home` by `gpgconf --kill`.
+async fn kill_agent(ty: SocketType, home: Option<&Path>) -> io::Result<()> {
+    let component = match ty {
+        SocketType::Dirmngr => "dirmngr",
+        _ => "gpg-agent",
+    };
+    let output = gpg_command("gpgconf", home)
+        .arg("--kill")
+        .arg(component)
         .output()
         .await?;
     if !output.status.success() {
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            format!(
-                "failed to start gpg-agent: {:?}",
-                String::from_utf8_lossy(&output.stderr)
-            ),
-        ));
+        return Err(other_error(format!(
+            "failed to kill {}: {:?}",
+            component,
+            String::from_utf8_lossy(&output.stderr)
+        )));
+    }
+    Ok(())
+}
+
+/// Starts gpg-agent of `home` by `startup` if it's not up yet.
+pub async fn ping_gpg_agent_with(startup: &AgentStartup, home: Option<&Path>) -> io::Result<()> {
+    let output = gpg_command(&startup.program, home)
+        .args(&startup.args)
+        .output()
+        .await?;
+    if !output.status.success() {
+        return Err(other_error(format!(
+            "failed to start gpg-agent: {:?}",
+            String::from_utf8_lossy(&output.stderr)
+        )));
     }
     Ok(())
 }
@@ -75,8 +298,17 @@ fn report_data_err(e: impl Into<Box<dyn error::Error + Send + Sync>>) -> io::Err
     io::Error::new(io::ErrorKind::InvalidData, e)
 }
 
+/// Parses the content of a Cygwin socket file after "!<socket >".
+///
+/// The content is written by `sprintf("%u %c %08x-%08x-%08x-%08x")` with the trailing NUL:
+/// - the port in decimal, followed by a space;
+/// - the socket type, 's' for stream sockets, followed by a space;
+/// - the nonce as 4 native 32 bits integers, each in 8 lowercase hex digits, separated by
+///   '-', 35 bytes in total;
+/// - a NUL.
+///
+/// For example, "4321 s 01234567-89abcdef-00000000-ffffffff\0".
 fn load_cygwin_port_nounce(buffer: &[u8]) -> io::Result<(u16, [u8; 16])> {
-    // "%u %c %08x-%08x-%08x-%08x\x00"
     let find = |buffer: &[u8], start_pos: usize, delimeter| {
         if buffer.len() <= start_pos {
             return Err(report_data_err("buffer to short"));
@@ -97,9 +329,12 @@ fn load_cygwin_port_nounce(buffer: &[u8]) -> io::Result<(u16, [u8; 16])> {
     let end_pos = find(buffer, 0, b' ')?;
     let port = parse(&buffer[..end_pos], 10)?;
 
-    if (1..=65535).contains(&port)
-        || !buffer[end_pos..].starts_with(b" s ")
+    // The socket type character is 's' for stream sockets, but is not required to be.
+    // Type and spaces take 3 bytes, then the nonce and the NUL.
+    if !(1..=65535).contains(&port)
         || buffer.len() < end_pos + 3 + 35 + 1
+        || !buffer[end_pos + 1].is_ascii_graphic()
+        || buffer[end_pos + 2] != b' '
     {
         return Err(report_data_err("wrong data format"));
     }
@@ -107,192 +342,2842 @@ fn load_cygwin_port_nounce(buffer: &[u8]) -> io::Result<(u16, [u8; 16])> {
     let mut start_pos = end_pos + 3;
     let mut nounce = [0u32; 4];
     for (pos, n) in nounce.iter_mut().enumerate() {
-        *n = parse(&buffer[start_pos..start_pos + 4], 16)?;
-        if pos < 3 {
-            if buffer[start_pos + 4] != b'-' {
-                return Err(report_data_err("wrong data format"));
-            }
-        } else if buffer[start_pos + 4] != b'x' {
+        let group = &buffer[start_pos..start_pos + 8];
+        // `from_str_radix` accepts a sign, which is not a hex digit.
+        if !group.iter().all(u8::is_ascii_hexdigit) {
+            return Err(report_data_err("wrong data format"));
+        }
+        *n = parse(group, 16)?;
+        let delimeter = if pos < 3 { b'-' } else { b'\0' };
+        if buffer[start_pos + 8] != delimeter {
             return Err(report_data_err("wrong data format"));
         }
-        start_pos += 5;
+        start_pos += 9;
     }
     // It's on purpose to ignore endianess.
-    Ok((port as u16, unsafe { mem::transmute(nounce) }))
+    Ok((port as u16, unsafe {
+        mem::transmute::<[u32; 4], [u8; 16]>(nounce)
+    }))
+}
+
+/// Number of attempts to read socket file before giving up.
+const SOCKET_FILE_READ_ATTEMPTS: usize = 5;
+const SOCKET_FILE_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+fn is_transient_file_error(e: &io::Error) -> bool {
+    // ERROR_SHARING_VIOLATION and ERROR_LOCK_VIOLATION.
+    e.kind() == io::ErrorKind::PermissionDenied || matches!(e.raw_os_error(), Some(32) | Some(33))
 }
 
-async fn load_port_nounce(path: &str) -> io::Result<(u16, [u8; 16])> {
-    if !Path::new(&path).exists() {
-        ping_gpg_agent().await?;
+/// Socket files only hold a port and nonce or a redirection, anything larger is not a socket
+/// file.
+const MAX_SOCKET_FILE_LEN: usize = 4096;
+
+/// Reads socket file at `path` into `buffer`.
+///
+/// Agent may be rewriting the file while starting up, so transient errors are retried and
+/// agent is pinged once in the middle. Files larger than `MAX_SOCKET_FILE_LEN` are rejected
+/// without reading the rest.
+async fn read_socket_file(
+    path: &Path,
+    buffer: &mut Vec<u8>,
+    startup: &AgentStartup,
+    home: Option<&Path>,
+) -> io::Result<()> {
+    let mut attempt = 1;
+    loop {
+        buffer.clear();
+        let res = async {
+            let f = File::open(path).await?;
+            let limit = MAX_SOCKET_FILE_LEN as u64 + 1;
+            f.take(limit).read_to_end(buffer).await?;
+            if buffer.len() > MAX_SOCKET_FILE_LEN {
+                return Err(report_data_err(format!(
+                    "{} is larger than {} bytes",
+                    path.display(),
+                    MAX_SOCKET_FILE_LEN
+                )));
+            }
+            Ok(())
+        }
+        .await;
+        match res {
+            Ok(_) => return Ok(()),
+            Err(e) if attempt < SOCKET_FILE_READ_ATTEMPTS && is_transient_file_error(&e) => {
+                debug!("failed to read {}: {:?}, retrying", path.display(), e);
+                if attempt == SOCKET_FILE_READ_ATTEMPTS / 2 {
+                    let _ = ping_gpg_agent_with(startup, home).await;
+                }
+                time::sleep(SOCKET_FILE_RETRY_INTERVAL).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(unavailable_reparse_target(path).unwrap_or(e)),
+        }
     }
-    let mut f = File::open(&path.replace('\\', "/")).await?;
-    let mut buffer = Vec::with_capacity(50);
-    f.read_to_end(&mut buffer).await?;
-    if buffer.starts_with(b"!<socket >") {
-        return load_cygwin_port_nounce(&buffer[10..]);
+}
+
+/// Redirection file can point to another redirection file, limit the depth to avoid loop.
+const MAX_REDIRECTS: usize = 4;
+
+/// Gets the target of socket redirection file.
+///
+/// Redirection file starts with a line "%Assuan%" and has a line "socket=<path>" pointing
+/// to the real socket.
+fn load_redirection(buffer: &[u8]) -> io::Result<Option<PathBuf>> {
+    if !buffer.starts_with(b"%Assuan%") {
+        return Ok(None);
     }
-    let (left, right) = buffer.split_at(buffer.len() - 16);
-    let to_port: u16 = str::from_utf8(left).unwrap().trim().parse().unwrap();
-    let mut nounce = [0; 16];
-    unsafe {
-        ptr::copy_nonoverlapping(right.as_ptr(), nounce.as_mut_ptr(), 16);
+    let content = match str::from_utf8(buffer) {
+        Ok(s) => s,
+        Err(e) => return Err(report_data_err(e)),
+    };
+    for line in content.lines().skip(1) {
+        if let Some(target) = line.trim().strip_prefix("socket=") {
+            return Ok(Some(PathBuf::from(target)));
+        }
     }
-    Ok((to_port, nounce))
+    Err(report_data_err("socket is missing in redirection file"))
 }
 
-async fn copy<'a>(
-    tag: &str,
-    from: &mut Pin<Box<dyn AsyncRead + Send + 'a>>,
-    to: &mut Pin<Box<dyn AsyncWrite + Send + 'a>>,
-) -> io::Result<u64> {
-    let mut buf = vec![0; 4096];
-    let mut total = 0;
+/// Format of the socket file that stores port and nonce.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SocketFormat {
+    /// Port followed by 16 bytes nonce, used by native gnupg.
+    Plain,
+    /// "!<socket >" followed by port and nonce in text, used by cygwin and msys.
+    Cygwin,
+}
+
+/// Where to connect for a socket file, and what to send before forwarding.
+#[derive(Clone)]
+pub struct AgentTarget {
+    /// Port of the agent listening on localhost.
+    pub port: u16,
+    /// Bytes sent right after connecting, `None` if the agent doesn't expect any.
+    pub handshake: Option<Vec<u8>>,
+}
+
+/// Loads `AgentTarget` from the content of a socket file in a specific format.
+///
+/// Redirection files are followed before the content is handed to a source.
+trait NonceSource: Send + Sync {
+    fn format(&self) -> SocketFormat;
+
+    /// Checks whether `content` is in the format of this source.
+    fn matches(&self, content: &[u8]) -> bool;
+
+    fn load(&self, content: &[u8]) -> io::Result<AgentTarget>;
+}
+
+/// Source of `SocketFormat::Plain`.
+struct PlainNonce;
+
+impl NonceSource for PlainNonce {
+    fn format(&self) -> SocketFormat {
+        SocketFormat::Plain
+    }
+
+    fn matches(&self, content: &[u8]) -> bool {
+        content.len() > 16
+    }
+
+    fn load(&self, content: &[u8]) -> io::Result<AgentTarget> {
+        let (left, right) = content.split_at(content.len() - 16);
+        let port = match str::from_utf8(left).map(|s| s.trim().parse()) {
+            Ok(Ok(port)) => port,
+            _ => return Err(report_data_err("wrong data format")),
+        };
+        Ok(AgentTarget {
+            port,
+            handshake: Some(right.to_vec()),
+        })
+    }
+}
+
+/// Source of `SocketFormat::Cygwin`.
+struct CygwinNonce;
+
+impl NonceSource for CygwinNonce {
+    fn format(&self) -> SocketFormat {
+        SocketFormat::Cygwin
+    }
+
+    fn matches(&self, content: &[u8]) -> bool {
+        content.starts_with(b"!<socket >")
+    }
+
+    fn load(&self, content: &[u8]) -> io::Result<AgentTarget> {
+        let (port, nonce) = load_cygwin_port_nounce(&content[10..])?;
+        Ok(AgentTarget {
+            port,
+            handshake: Some(nonce.to_vec()),
+        })
+    }
+}
+
+/// Sources tried in order, more specific formats should go first.
+static NONCE_SOURCES: &[&dyn NonceSource] = &[&CygwinNonce, &PlainNonce];
+
+/// Loads `content` of a socket file by the first source in `sources` that matches it.
+fn parse_socket_content(
+    sources: &[&dyn NonceSource],
+    content: &[u8],
+) -> io::Result<(SocketFormat, AgentTarget)> {
+    let source = match sources.iter().find(|s| s.matches(content)) {
+        Some(s) => s,
+        None => return Err(report_data_err("unknown socket format")),
+    };
+    Ok((source.format(), source.load(content)?))
+}
+
+/// Information parsed from socket file.
+pub struct SocketInfo {
+    /// Socket files followed, the last one is where port and nonce are loaded.
+    pub paths: Vec<PathBuf>,
+    pub format: SocketFormat,
+    pub target: AgentTarget,
+}
+
+async fn load_socket_info(
+    path: &Path,
+    startup: &AgentStartup,
+    home: Option<&Path>,
+) -> io::Result<SocketInfo> {
+    if !path.exists() {
+        // Starting agent can't fix a broken junction, report it directly.
+        if let Some(e) = unavailable_reparse_target(path) {
+            return Err(e);
+        }
+        ping_gpg_agent_with(startup, home).await?;
+    }
+    let mut paths = vec![path.to_owned()];
+    let mut buffer = Vec::with_capacity(50);
     loop {
-        let cnt = from.read(&mut buf).await?;
-        if cnt == 0 {
-            to.shutdown().await?;
-            unsafe {
-                ptr::write_bytes(buf.as_mut_ptr(), 0, 4096);
+        read_socket_file(paths.last().unwrap(), &mut buffer, startup, home).await?;
+        match load_redirection(&buffer)? {
+            Some(target) if paths.len() <= MAX_REDIRECTS => {
+                debug!(
+                    "socket {} is redirected to {}",
+                    paths.last().unwrap().display(),
+                    target.display()
+                );
+                paths.push(target);
             }
-            return Ok(total);
+            Some(_) => return Err(report_data_err("too many socket redirections")),
+            None => break,
         }
-        total += cnt as u64;
-        trace!("{} {:?}", tag, String::from_utf8_lossy(&buf[..cnt]));
-        to.write_all(&buf[..cnt]).await?;
     }
+    let (format, target) = parse_socket_content(NONCE_SOURCES, &buffer)?;
+    Ok(SocketInfo {
+        paths,
+        format,
+        target,
+    })
 }
 
-async fn delegate(mut from: impl SplitStream, to_port: u16, nounce: [u8; 16]) -> io::Result<()> {
-    let mut delegate = match TcpStream::connect(("127.0.0.1", to_port)).await {
+async fn load_agent_target(
+    path: &Path,
+    startup: &AgentStartup,
+    home: Option<&Path>,
+) -> io::Result<AgentTarget> {
+    let target = load_socket_info(path, startup, home).await?.target;
+    let zero_nonce = target
+        .handshake
+        .as_ref()
+        .is_some_and(|h| h.iter().all(|b| *b == 0));
+    if target.port == 0 || zero_nonce {
+        // Agent may crash when writing the file, let it start again.
+        let _ = ping_gpg_agent_with(startup, home).await;
+        return Err(report_data_err(format!(
+            "{} has zero port or nonce, agent may be restarting",
+            path.display()
+        )));
+    }
+    Ok(target)
+}
+
+static DISCOVERY_COMMAND: OnceLock<AgentStartup> = OnceLock::new();
+
+/// Sets the command that prints port and nonce of the agent serving extra socket, which is
+/// used instead of loading the socket file. It can only be set once.
+///
+/// The command runs with GNUPGHOME of the agent, and should print a line `port <PORT>` and
+/// optionally a line `nonce <HEX>` of 16 bytes, other lines are ignored.
+pub fn set_discovery_command(cmd: AgentStartup) -> io::Result<()> {
+    DISCOVERY_COMMAND.set(cmd).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "discovery command is already set",
+        )
+    })
+}
+
+/// Parses the output of discovery command, see `set_discovery_command`.
+fn parse_discovery_output(output: &[u8]) -> io::Result<AgentTarget> {
+    let output = match str::from_utf8(output) {
         Ok(s) => s,
-        Err(e) => {
-            // It's possible that gpg-client was killed and leave stale meta untouched.
-            // Reping agent to make it startup.
-            let _ = ping_gpg_agent().await;
-            return Err(e);
+        Err(e) => return Err(report_data_err(e)),
+    };
+    let (mut port, mut nonce) = (None, None);
+    for line in output.lines() {
+        let line = line.trim();
+        let (key, value) = match line.find(|c: char| c == '=' || c.is_whitespace()) {
+            Some(pos) => (&line[..pos], line[pos + 1..].trim()),
+            None => continue,
+        };
+        match key {
+            "port" if port.is_none() => match value.parse::<u16>() {
+                Ok(p) if p != 0 => port = Some(p),
+                _ => return Err(report_data_err(format!("invalid port {:?}", value))),
+            },
+            "nonce" if nonce.is_none() => nonce = Some(parse_hex_nonce(value)?),
+            "port" | "nonce" => {
+                return Err(report_data_err(format!("duplicated {}", key)));
+            }
+            _ => (),
         }
+    }
+    match port {
+        Some(port) => Ok(AgentTarget {
+            port,
+            handshake: nonce.map(|n: [u8; 16]| n.to_vec()),
+        }),
+        None => Err(report_data_err("port is missing in discovery output")),
+    }
+}
+
+fn parse_hex_nonce(value: &str) -> io::Result<[u8; 16]> {
+    let invalid = || report_data_err("nonce should be 32 hex digits");
+    let value = value.as_bytes();
+    if value.len() != 32 || !value.iter().all(u8::is_ascii_hexdigit) {
+        return Err(invalid());
+    }
+    let mut nonce = [0; 16];
+    for (b, pair) in nonce.iter_mut().zip(value.chunks(2)) {
+        // All ASCII hex digits, so it can't fail.
+        *b = u8::from_str_radix(str::from_utf8(pair).unwrap(), 16).unwrap();
+    }
+    Ok(nonce)
+}
+
+async fn run_discovery_command(cmd: &AgentStartup, home: Option<&Path>) -> io::Result<AgentTarget> {
+    let output = gpg_command(&cmd.program, home)
+        .args(&cmd.args)
+        .output()
+        .await?;
+    if !output.status.success() {
+        return Err(other_error(format!(
+            "discovery command failed with {}: {:?}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        )));
+    }
+    if output.stdout.len() > MAX_SOCKET_FILE_LEN {
+        return Err(report_data_err(format!(
+            "discovery output is larger than {} bytes",
+            MAX_SOCKET_FILE_LEN
+        )));
+    }
+    parse_discovery_output(&output.stdout)
+}
+
+/// Parses the socket file at `path`, which is discovered the same way as bridging extra
+/// socket if it's `None`.
+///
+/// It's only for debugging, as the nonce is a secret. Agent is started by `startup` if the
+/// file is missing.
+pub async fn parse_socket(path: Option<PathBuf>, startup: &AgentStartup) -> io::Result<SocketInfo> {
+    let path = match path {
+        Some(p) => p,
+        None => load_gpg_socket_path(SocketType::Extra, None).await?,
     };
-    trace!("--> {:?}", String::from_utf8_lossy(&nounce));
-    delegate.write_all(&nounce).await?;
-    delegate.flush().await?;
+    load_socket_info(&path, startup, None).await
+}
 
-    let (mut source_read, mut source_write) = from.split_rw();
-    let (mut target_read, mut target_write) = delegate.split_rw();
-    let s2t = copy("-->", &mut source_read, &mut target_write);
-    let t2s = copy("<--", &mut target_read, &mut source_write);
-    let (received, replied) = tokio::join!(s2t, t2s);
-    debug!(
-        "connection finished, received {}, replied {}",
-        received?, replied?
-    );
-    Ok(())
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(0);
+/// Bytes received from and replied to clients by closed connections.
+static BYTES_RECEIVED: AtomicU64 = AtomicU64::new(0);
+static BYTES_REPLIED: AtomicU64 = AtomicU64::new(0);
+
+fn add_bytes(received: u64, replied: u64) {
+    BYTES_RECEIVED.fetch_add(received, Ordering::Relaxed);
+    BYTES_REPLIED.fetch_add(replied, Ordering::Relaxed);
 }
 
-/// A bridge that forwards all requests from certain stream to gpg-agent on Windows.
+/// Aggregate counters of forwarded connections.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    /// Connections forwarded by the bridge since start.
+    pub connections: u64,
+    /// Connections being forwarded.
+    pub active: usize,
+    /// Bytes received from clients, only counted when connections are closed.
+    pub received: u64,
+    /// Bytes replied to clients, only counted when connections are closed.
+    pub replied: u64,
+    /// Time since the bridge is created.
+    pub uptime: Duration,
+    /// Whether the last connection to extra socket reached the agent, `None` if no
+    /// connection has been forwarded yet.
+    pub agent_reachable: Option<bool>,
+}
+
+fn next_connection_id() -> u64 {
+    NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// How a stream connection is relayed to agent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum StreamMode {
+    Plain,
+    /// Client data is wrapped in frames, see `Framed`.
+    Framed,
+    /// Only informational commands are forwarded, see `Bridge::serve_probe`.
+    ProbeOnly,
+}
+
+/// Why a forwarded connection is closed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum CloseReason {
+    /// Client closes its side first.
+    ClientEof,
+    /// Agent closes its side first.
+    AgentEof,
+    /// Either side fails.
+    Error,
+    /// No data is transferred within idle timeout, or agent doesn't reply in time.
+    Timeout,
+    /// Bridge is exiting, see `Bridge::close_connections`.
+    Shutdown,
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            CloseReason::ClientEof => "client_eof",
+            CloseReason::AgentEof => "agent_eof",
+            CloseReason::Error => "error",
+            CloseReason::Timeout => "timeout",
+            CloseReason::Shutdown => "shutdown",
+        };
+        f.write_str(name)
+    }
+}
+
+/// An error of reading from or writing to agent, as opposed to the client.
 ///
-/// `to_path` should point to the path of gnupg UDS. `from_addr` can be either TCP address
-/// or Named Pipe.
-// TODO: use trait to unify access.
-pub async fn bridge(ty: SocketType, from_addr: String, to_path: Option<String>) -> io::Result<()> {
-    // Attempt to setup gpg-agent if it's not up yet.
-    let _ = ping_gpg_agent().await;
-    // We can also try to guess ':'. But then we can distinguish between named pipe localhost and
-    // invalid tcp address localhost. Force check '\pipe\' can allow those address fail with clear
-    // error.
-    if from_addr.starts_with("\\\\.\\pipe\\") {
-        let server = ServerOptions::new()
-            .first_pipe_instance(true)
-            .create(&from_addr)?;
-        let listener = NamedPipeServerListener::new(server, from_addr);
-        bridge_listener(ty, listener, to_path).await?;
-    } else {
-        let listener = TcpListener::bind(&from_addr).await?;
-        bridge_listener(ty, listener, to_path).await?;
+/// Only errors of agent side mean the agent may be wedged, clients can fail or go away for
+/// any reason.
+#[derive(Debug)]
+pub(crate) struct AgentSideError(io::Error);
+
+impl AgentSideError {
+    /// Marks `e` as caused by agent, keeping its kind.
+    pub(crate) fn wrap(e: io::Error) -> io::Error {
+        io::Error::new(e.kind(), AgentSideError(e))
+    }
+
+    /// Checks if `e` is caused by agent.
+    fn is(e: &io::Error) -> bool {
+        e.get_ref().is_some_and(|e| e.is::<AgentSideError>())
     }
-    Ok(())
 }
 
-async fn bridge_listener<L>(ty: SocketType, listener: L, to_path: Option<String>) -> io::Result<()>
-where
-    L: Listener,
-    L::Connection: SplitStream + Send + 'static,
-{
-    match ty {
-        SocketType::Extra => bridge_to_stream(listener, to_path).await?,
-        SocketType::Ssh => bridge_to_message(listener).await?,
+impl fmt::Display for AgentSideError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "agent side: {}", self.0)
+    }
+}
+
+impl error::Error for AgentSideError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+/// Default size of the buffer reading ssh requests, which fits most requests.
+pub const DEFAULT_SSH_READ_BUFFER: usize = 8192;
+
+/// Default buffer size of `copy` at start, which fits typical Assuan exchanges.
+pub const DEFAULT_COPY_BUF_SIZE: usize = 4096;
+/// Buffer size `copy` can grow to for large transfers, unless it starts larger.
+const MAX_COPY_BUF_SIZE: usize = 65536;
+/// Full reads in a row before the buffer of `copy` is doubled.
+const COPY_GROW_READS: usize = 2;
+/// Reads below a quarter of the buffer in a row before it's halved.
+const COPY_SHRINK_READS: usize = 4;
+
+/// States of a forwarded connection shared by both directions of `copy`.
+struct Transfer {
+    /// Why the connection is closed, recorded by the direction that finishes first.
+    reason: OnceLock<CloseReason>,
+    /// When data is read or written in either direction last time.
+    active_at: parking_lot::Mutex<time::Instant>,
+}
+
+impl Transfer {
+    fn new() -> Transfer {
+        Transfer {
+            reason: OnceLock::new(),
+            active_at: parking_lot::Mutex::new(time::Instant::now()),
+        }
+    }
+
+    fn touch(&self) {
+        *self.active_at.lock() = time::Instant::now();
     }
-    Ok(())
 }
 
-async fn bridge_to_stream<L>(mut listener: L, to_path: Option<String>) -> io::Result<()>
-where
-    L: Listener,
-    L::Connection: SplitStream + Send + 'static,
-{
-    let meta = Arc::new(Mutex::new(AgentMeta {
-        path: to_path,
-        args: None,
-    }));
+/// Copies all data from `from` to `to` and counts them in `total`, data is passed to
+/// `inspect` before written.
+///
+/// The buffer starts with `buf_size` and is doubled when reads keep filling it, then halved
+/// when reads stay small again, but never below `buf_size`. Replaced buffers are zeroed as
+/// well.
+///
+/// `eof` is recorded to `transfer` when `from` reaches EOF, unless the other direction has
+/// recorded its reason already. It also tells the direction in trace logs, and which side is
+/// agent, whose errors are wrapped in `AgentSideError`. `transfer` is touched after every read
+/// and write, so a slow write of a large chunk is not mistaken for an idle connection.
+async fn copy<'a>(
+    from: &mut Pin<Box<dyn AsyncRead + Send + 'a>>,
+    to: &mut Pin<Box<dyn AsyncWrite + Send + 'a>>,
+    mut inspect: impl FnMut(&[u8]),
+    total: &mut u64,
+    eof: CloseReason,
+    transfer: &Transfer,
+    buf_size: usize,
+) -> io::Result<()> {
+    let max_size = buf_size.max(MAX_COPY_BUF_SIZE);
+    // Only the direction from client ends with `ClientEof`.
+    let to_agent = eof == CloseReason::ClientEof;
+    let tag = if to_agent { "-->" } else { "<--" };
+    let mark = |e, agent_side| match agent_side {
+        true => AgentSideError::wrap(e),
+        false => e,
+    };
+    let mut buf = ScrubBuf::new(buf_size);
+    let (mut full, mut small) = (0, 0);
     loop {
-        let conn = listener.accept().await?;
-
-        let meta = meta.clone();
-        let (port, nounce) = {
-            let mut m = meta.lock().await;
-            if m.args.is_none() {
-                if m.path.is_none() {
-                    m.path = Some(load_gpg_socket_path(SocketType::Extra).await?);
-                }
-                m.args = Some(load_port_nounce(m.path.as_ref().unwrap()).await?);
-            }
-            m.args.unwrap()
-        };
+        let cnt = from
+            .read(&mut buf.0)
+            .await
+            .map_err(|e| mark(e, !to_agent))?;
+        if cnt == 0 {
+            let _ = transfer.reason.set(eof);
+            to.shutdown().await.map_err(|e| mark(e, to_agent))?;
+            return Ok(());
+        }
+        transfer.touch();
+        *total += cnt as u64;
+        let data = &buf.0[..cnt];
+        trace!("{} {}", tag, loggable(data));
+        inspect(data);
+        to.write_all(data).await.map_err(|e| mark(e, to_agent))?;
+        transfer.touch();
 
-        tokio::spawn(async move {
-            if let Err(e) = delegate(conn, port, nounce).await {
-                error!("failed to delegate stream: {:?}", e);
-                meta.lock().await.args.take();
-            }
-        });
+        let size = buf.0.len();
+        if cnt == size {
+            full += 1;
+            small = 0;
+        } else if cnt < size / 4 {
+            small += 1;
+            full = 0;
+        } else {
+            full = 0;
+            small = 0;
+        }
+        if full >= COPY_GROW_READS && size < max_size {
+            buf = ScrubBuf::new((size * 2).min(max_size));
+            full = 0;
+        } else if small >= COPY_SHRINK_READS && size > buf_size {
+            buf = ScrubBuf::new((size / 2).max(buf_size));
+            small = 0;
+        }
     }
 }
 
-// For now, forwarding ssh agent requests can only be done using IPC messages. gpg
-// ssh agent seems to do security trick on tcp stream and fail to receive anything.
-async fn delegate_ssh(mut from: impl SplitStream) -> io::Result<()> {
-    let (mut source_read, mut source_write) = from.split_rw();
-    let mut handler = ssh::Handler::new().await?;
-    while let Some(resp) = handler.process_one(&mut source_read).await? {
-        trace!("get {:?}", String::from_utf8_lossy(resp));
-        source_write.write_all(resp).await?;
+/// Connects to agent listening on `port` of localhost, from `bind_addr` if specified.
+async fn connect_agent(port: u16, bind_addr: Option<IpAddr>) -> io::Result<TcpStream> {
+    let target = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+    let bind_addr = match bind_addr {
+        Some(addr) => addr,
+        None => return TcpStream::connect(target).await,
+    };
+    if !bind_addr.is_ipv4() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} can't be used to connect to {}", bind_addr, target),
+        ));
     }
-    debug!(
-        "connection finished, received {}, replied {}",
-        handler.received(),
-        handler.replied()
-    );
-    Ok(())
+    let socket = TcpSocket::new_v4()?;
+    socket.bind(SocketAddr::new(bind_addr, 0))?;
+    socket.connect(target).await
 }
 
-async fn bridge_to_message<L>(mut listener: L) -> io::Result<()>
-where
-    L: Listener,
-    L::Connection: SplitStream + Send + 'static,
-{
-    let reload = Arc::new(AtomicBool::new(false));
-    loop {
-        let conn = listener.accept().await?;
+/// Checks whether connecting to `port` of localhost reaches listener `addr`.
+///
+/// Host names are not resolved, so listeners bound by host name are never matched.
+fn reaches_listener(addr: &str, port: u16) -> bool {
+    match parse_listen_addr(addr) {
+        Ok(ListenKind::Tcp(a)) => {
+            a.port() == port && a.is_ipv4() && (a.ip().is_loopback() || a.ip().is_unspecified())
+        }
+        _ => false,
+    }
+}
 
-        if reload.load(Ordering::SeqCst) {
-            ping_gpg_agent().await?;
-            reload.store(false, Ordering::SeqCst);
+fn forwarding_loop_error(port: u16) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!(
+            "agent port {} is listened by gpg-bridge itself, requests would be forwarded in a \
+             loop, check the socket file",
+            port
+        ),
+    )
+}
+
+/// Options of connecting to agent.
+#[derive(Clone, Copy)]
+struct AgentOptions {
+    /// Local address to connect from.
+    bind_addr: Option<IpAddr>,
+    /// Whether to use standard socket if extra socket can't be loaded.
+    fallback_standard: bool,
+    /// How long an agent is considered down after it fails to open.
+    failure_cooldown: Option<Duration>,
+}
+
+#[cfg(test)]
+thread_local! {
+    /// Number of times agent target is resolved on current thread.
+    static DISCOVERIES: std::cell::Cell<usize> = Default::default();
+}
+
+async fn resolve_agent_target(
+    meta: &mut AgentMeta,
+    ty: SocketType,
+    startup: &AgentStartup,
+    home: Option<&Path>,
+    fallback_standard: bool,
+) -> io::Result<AgentTarget> {
+    #[cfg(test)]
+    DISCOVERIES.with(|d| d.set(d.get() + 1));
+    if ty == SocketType::Extra {
+        if let Some(cmd) = DISCOVERY_COMMAND.get() {
+            return run_discovery_command(cmd, home).await;
         }
-        let reload = reload.clone();
-        tokio::spawn(async move {
-            if let Err(e) = delegate_ssh(conn).await {
-                error!("failed to delegate message: {:?}", e);
-                reload.store(true, Ordering::SeqCst);
+    }
+    // Killing agent by `gpgconf --kill` may remove the socket directory as well, which is
+    // not recreated until agent starts. The path may change after restart, so it's
+    // discovered again.
+    if let Some(dir) = meta.path.as_deref().and_then(Path::parent) {
+        if !dir.as_os_str().is_empty() && !dir.exists() {
+            debug!(
+                "socket directory {} is missing, restarting agent",
+                dir.display()
+            );
+            let _ = ping_gpg_agent_with(startup, home).await;
+            if meta.discovered {
+                meta.path = None;
             }
-        });
+        }
+    }
+    let res = async {
+        if meta.path.is_none() {
+            meta.path = Some(load_gpg_socket_path(ty, home).await?);
+            meta.discovered = true;
+        }
+        load_agent_target(meta.path.as_ref().unwrap(), startup, home).await
+    }
+    .await;
+    match res {
+        Err(e) if fallback_standard && ty == SocketType::Extra => {
+            warn!(
+                "failed to load extra socket: {:?}, falling back to standard socket",
+                e
+            );
+            let path = gpgconf_list_dir("agent-socket", home).await?;
+            let target = load_agent_target(&path, startup, home).await?;
+            meta.path = Some(path);
+            meta.discovered = true;
+            Ok(target)
+        }
+        res => res,
+    }
+}
+
+/// Connects to `agent` and authenticates the connection with nonce.
+///
+/// Agent listening on a port of `core` is refused before connecting, otherwise every
+/// connection accepted by the port connects to itself again.
+async fn open_agent(agent: &Agent, core: &BridgeCore) -> io::Result<TcpStream> {
+    let opts = core.agent_opts;
+    let home = agent.home.as_deref();
+    let target = {
+        let mut m = agent.meta.lock().await;
+        if m.target.is_none() {
+            let start = Instant::now();
+            let startup = core.startup(agent.ty);
+            let fallback = opts.fallback_standard;
+            let target = resolve_agent_target(&mut m, agent.ty, startup, home, fallback).await?;
+            if trace_timing() {
+                debug!("{:?} agent discovered in {:?}", agent.ty, start.elapsed());
+            }
+            m.target = Some(target);
+        }
+        m.target.clone().unwrap()
+    };
+    let start = Instant::now();
+    if core.listens_on(target.port) {
+        return Err(forwarding_loop_error(target.port));
+    }
+    let mut delegate = match connect_agent(target.port, opts.bind_addr).await {
+        Ok(s) => s,
+        Err(e) => {
+            // It's possible that gpg-client was killed and leave stale meta untouched.
+            // Reping agent to make it startup.
+            let _ = ping_gpg_agent_with(core.startup(agent.ty), home).await;
+            return Err(e);
+        }
+    };
+    if let Some(handshake) = &target.handshake {
+        trace!("--> {}", loggable(handshake));
+        delegate.write_all(handshake).await?;
+        delegate.flush().await?;
+    }
+    if trace_timing() {
+        debug!("{:?} agent connected in {:?}", agent.ty, start.elapsed());
+    }
+    Ok(delegate)
+}
+
+/// Default times to retry if the first connection to an agent fails.
+pub const DEFAULT_WARMUP_RETRIES: usize = 2;
+const WARMUP_RETRY_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Opens `agent` with retries, or its fallbacks in order if it fails.
+///
+/// The agent opened last time is tried first, so a failed primary agent is not tried for
+/// every connection. It's only tried again when the fallback fails as well.
+async fn open_agent_with_failover(
+    agent: &Agent,
+    core: &BridgeCore,
+    retries: usize,
+) -> io::Result<TcpStream> {
+    let current = agent.current.load(Ordering::Relaxed);
+    let mut tried = current;
+    let mut res = open_agent_with_retry(agent.backend(current), core, retries).await;
+    for i in (0..=agent.fallbacks.len()).filter(|i| *i != current) {
+        let e = match res {
+            Ok(s) => return Ok(s),
+            Err(e) => e,
+        };
+        warn!(
+            "failed to open agent backend {}: {:?}, trying backend {}",
+            tried, e, i
+        );
+        tried = i;
+        res = open_agent_with_retry(agent.backend(i), core, 0).await;
+        if res.is_ok() {
+            info!("agent backend {} is used from now on", i);
+            agent.current.store(i, Ordering::Relaxed);
+        }
+    }
+    res
+}
+
+/// Same as `open_agent`, but retries at most `retries` times with reloaded meta.
+///
+/// If it still fails, the agent is considered down for `failure_cooldown`, during which
+/// the same error is returned without discovering, connecting or pinging the agent.
+async fn open_agent_with_retry(
+    agent: &Agent,
+    core: &BridgeCore,
+    retries: usize,
+) -> io::Result<TcpStream> {
+    if let Some((until, kind, msg)) = &agent.meta.lock().await.down {
+        if time::Instant::now() < *until {
+            return Err(io::Error::new(
+                *kind,
+                format!("agent is down, last error: {}", msg),
+            ));
+        }
+    }
+    let mut attempt = 0;
+    loop {
+        match open_agent(agent, core).await {
+            Ok(s) => {
+                agent.meta.lock().await.down = None;
+                return Ok(s);
+            }
+            Err(e) => {
+                let mut m = agent.meta.lock().await;
+                m.target.take();
+                if attempt >= retries {
+                    if let Some(cooldown) = core.agent_opts.failure_cooldown {
+                        debug!("{:?} agent is down for {:?}", agent.ty, cooldown);
+                        m.down = Some((time::Instant::now() + cooldown, e.kind(), e.to_string()));
+                    }
+                    return Err(e);
+                }
+                drop(m);
+                attempt += 1;
+                warn!("failed to open agent: {:?}, retry {}", e, attempt);
+                let home = agent.home.as_deref();
+                let _ = ping_gpg_agent_with(core.startup(agent.ty), home).await;
+                time::sleep(WARMUP_RETRY_INTERVAL).await;
+            }
+        }
+    }
+}
+
+/// How `delegate` forwards a stream connection.
+#[derive(Clone, Copy, Debug)]
+struct DelegateOptions {
+    /// Initial buffer size of `copy`.
+    buf_size: usize,
+    /// Closes the connection if no data is transferred in the duration.
+    idle_timeout: Option<Duration>,
+}
+
+async fn delegate(
+    id: u64,
+    ty: SocketType,
+    mut from: impl SplitStream,
+    mut delegate: impl SplitStream,
+    mut audit: Option<AuditSession>,
+    opts: DelegateOptions,
+    stop: impl Future<Output = ()>,
+) -> io::Result<()> {
+    let DelegateOptions {
+        buf_size,
+        idle_timeout,
+    } = opts;
+    let (mut source_read, mut source_write) = from.split_rw();
+    let (mut target_read, mut target_write) = delegate.split_rw();
+    let transfer = Transfer::new();
+    let (mut received, mut replied) = (0, 0);
+    let mut pinentry = Some(PinentryWatch::new(id)).filter(|_| ty == SocketType::Extra);
+    let s2t = copy(
+        &mut source_read,
+        &mut target_write,
+        |data: &[u8]| {
+            if let Some(audit) = &mut audit {
+                audit.feed(data);
+            }
+        },
+        &mut received,
+        CloseReason::ClientEof,
+        &transfer,
+        buf_size,
+    );
+    let t2s = copy(
+        &mut target_read,
+        &mut source_write,
+        |data: &[u8]| {
+            if let Some(pinentry) = &mut pinentry {
+                pinentry.feed(data);
+            }
+        },
+        &mut replied,
+        CloseReason::AgentEof,
+        &transfer,
+        buf_size,
+    );
+    let idle = async {
+        let timeout = match idle_timeout {
+            Some(t) => t,
+            None => return std::future::pending().await,
+        };
+        loop {
+            let deadline = *transfer.active_at.lock() + timeout;
+            if time::Instant::now() >= deadline {
+                return io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("no data is transferred in {:?}", timeout),
+                );
+            }
+            time::sleep_until(deadline).await;
+        }
+    };
+    let (res, reason) = tokio::select! {
+        res = async { tokio::join!(s2t, t2s) } => match res {
+            (Err(e), _) | (_, Err(e)) => (Err(e), CloseReason::Error),
+            _ => (Ok(()), *transfer.reason.get().unwrap()),
+        },
+        e = idle => {
+            let _ = source_write.shutdown().await;
+            let _ = target_write.shutdown().await;
+            (Err(e), CloseReason::Timeout)
+        }
+        _ = stop => {
+            let _ = source_write.shutdown().await;
+            let _ = target_write.shutdown().await;
+            (Ok(()), CloseReason::Shutdown)
+        }
+    };
+    // Agent greets every connection, but a socket without enough privileges, like the
+    // restricted extra socket, may close right after the client's first command.
+    if reason == CloseReason::AgentEof
+        && received > 0
+        && pinentry.as_ref().is_some_and(|p| p.greeting_only())
+    {
+        warn!(
+            "connection {}: agent closed the connection right after greeting, the socket may be \
+             of a wrong type, check --extra-socket or try --extra-fallback-standard",
+            id
+        );
+    }
+    add_bytes(received, replied);
+    debug!(
+        "connection {} ({}) closed: {}, received {}, replied {}",
+        id,
+        ty.short_name(),
+        reason,
+        received,
+        replied
+    );
+    res
+}
+
+/// Size of the payload round tripped by `loopback_test`, larger than the buffers of both pipe
+/// and `copy`, so partial reads and writes are exercised.
+const LOOPBACK_PAYLOAD_LEN: usize = 256 * 1024;
+const LOOPBACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn loopback_timed_out(_: time::error::Elapsed) -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "loopback test timed out")
+}
+
+/// Round trips a payload through a temporary Named Pipe, which is forwarded the same way as
+/// extra socket but to an in-process echo server instead of agent.
+///
+/// It tells whether forwarding works without involving agent. Returns the number of bytes
+/// round tripped.
+pub async fn loopback_test() -> io::Result<usize> {
+    let addr = format!("\\\\.\\pipe\\gpg-bridge-loopback-{}", std::process::id());
+    let mut listener = bind(&addr, &BindOptions::default()).await?;
+    let echo = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    let echo_addr = echo.local_addr()?;
+    let echo_task = tokio::spawn(async move {
+        let (mut conn, _) = echo.accept().await?;
+        let (mut r, mut w) = conn.split();
+        tokio::io::copy(&mut r, &mut w).await?;
+        w.shutdown().await
+    });
+
+    let client = ClientOptions::new().open(&addr)?;
+    let conn = Listener::accept(&mut listener).await?;
+    let target = TcpStream::connect(echo_addr).await?;
+    let id = next_connection_id();
+    let forward = tokio::spawn(delegate(
+        id,
+        SocketType::Extra,
+        conn,
+        target,
+        None,
+        DelegateOptions {
+            buf_size: DEFAULT_COPY_BUF_SIZE,
+            idle_timeout: None,
+        },
+        std::future::pending(),
+    ));
+
+    let payload: Vec<u8> = (0..LOOPBACK_PAYLOAD_LEN).map(|i| (i % 251) as u8).collect();
+    let (mut r, mut w) = tokio::io::split(client);
+    let write = async {
+        w.write_all(&payload).await?;
+        w.flush().await
+    };
+    let read = async {
+        let mut echoed = vec![0; payload.len()];
+        r.read_exact(&mut echoed).await?;
+        Ok(echoed)
+    };
+    let (_, echoed) = time::timeout(LOOPBACK_TIMEOUT, async { tokio::try_join!(write, read) })
+        .await
+        .map_err(loopback_timed_out)??;
+    if echoed != payload {
+        return Err(report_data_err("echoed data differs from sent data"));
+    }
+    // Closing the client should close every connection in the chain.
+    drop((r, w));
+    for task in [forward, echo_task] {
+        time::timeout(LOOPBACK_TIMEOUT, task)
+            .await
+            .map_err(loopback_timed_out)?
+            .map_err(|e| other_error(format!("loopback task failed: {}", e)))??;
+    }
+    Ok(payload.len())
+}
+
+/// What a successor process needs to take over a listener.
+#[derive(Clone)]
+struct Handoff {
+    /// The address that is actually bound.
+    addr: String,
+    /// `None` for Named Pipe.
+    socket: Option<RawSocket>,
+}
+
+impl Handoff {
+    fn new(addr: &str, listener: &AnyListener) -> Handoff {
+        Handoff {
+            addr: addr.to_owned(),
+            socket: listener.raw_socket(),
+        }
+    }
+}
+
+struct ListenerEntry {
+    ty: SocketType,
+    home: Option<PathBuf>,
+    stop: Arc<Notify>,
+    /// `None` until the address is bound.
+    handoff: Option<Handoff>,
+}
+
+/// An active listener of a `Bridge`.
+#[derive(Clone, Debug)]
+pub struct ListenerInfo {
+    pub ty: SocketType,
+    pub addr: String,
+    /// GNUPGHOME of the agent requests are forwarded to.
+    pub gnupghome: Option<PathBuf>,
+}
+
+/// A connection that is about to be forwarded.
+#[derive(Clone, Debug)]
+pub struct ConnectionInfo {
+    /// The id used in logs.
+    pub id: u64,
+    pub ty: SocketType,
+    pub peer: Peer,
+}
+
+/// Decides whether a connection can be forwarded, it may take time like asking the user.
+pub type AcceptFilter = Arc<dyn Fn(&ConnectionInfo) -> BoxFuture<'static, bool> + Send + Sync>;
+
+/// Options of a `Bridge`.
+#[derive(Default)]
+pub struct BridgeOptions {
+    /// Path of gnupg extra socket of the inherited GNUPGHOME. If it's `None`, the path will
+    /// be discovered by `gpgconf` when needed.
+    pub extra_socket: Option<PathBuf>,
+    /// Paths of extra sockets of other agents, tried in order when the agent of
+    /// `extra_socket` can't be connected.
+    pub extra_socket_fallbacks: Vec<PathBuf>,
+    /// Path of dirmngr socket of the inherited GNUPGHOME. If it's `None`, the path will be
+    /// discovered by `gpgconf` when needed.
+    pub dirmngr_socket: Option<PathBuf>,
+    /// Records requests forwarded to extra socket if specified.
+    pub audit_extra: Option<Auditor>,
+    /// Options to bind listening addresses.
+    pub bind: BindOptions,
+    /// Whether to move Named Pipes under `\\.\pipe\Local\` and only grant the current logon
+    /// session access. See `util::local_pipe_addr` and `PipeSecurity::session_only`.
+    pub pipe_local_namespace: bool,
+    /// Local address to connect to agent from.
+    pub agent_bind_addr: Option<IpAddr>,
+    /// How many times to retry if the first connection to an agent fails,
+    /// `DEFAULT_WARMUP_RETRIES` if `None`.
+    pub warmup_retries: Option<usize>,
+    /// Command that starts an agent when it's not up.
+    pub agent_startup: AgentStartup,
+    /// Command that starts dirmngr when it's not up, `gpg-connect-agent --dirmngr /bye` if
+    /// it's `None`.
+    pub dirmngr_startup: Option<AgentStartup>,
+    /// Whether to forward to standard socket if extra socket can't be loaded.
+    pub extra_fallback_standard: bool,
+    /// Whether `Bridge::upgrade` is allowed.
+    pub reexec_on_upgrade: bool,
+    /// Whether clients of extra socket over TCP send and receive length delimited frames.
+    pub framed: bool,
+    /// Whether framed clients send a correlation id as the first frame, which is logged with
+    /// the connection id.
+    pub framed_correlation: bool,
+    /// Deadline of processing a ssh request.
+    pub ssh_request_timeout: Option<Duration>,
+    /// Closes a connection if no data is transferred in either direction for the given time.
+    /// For ssh, it's the time waiting for the next request.
+    pub idle_timeout: Option<Duration>,
+    /// After an agent fails to open, new connections to it fail immediately for the given
+    /// time instead of trying again.
+    pub agent_failure_cooldown: Option<Duration>,
+    /// Size of the buffer reading ssh requests, so a request is usually read by one call
+    /// instead of two, `DEFAULT_SSH_READ_BUFFER` if it's `None`. Requests are read directly
+    /// if it's 0.
+    pub ssh_read_buffer: Option<usize>,
+    /// Logs the fingerprint of the key used by every ssh sign request.
+    pub ssh_log_signs: bool,
+    /// Checks every accepted connection before forwarding if specified.
+    pub accept_filter: Option<AcceptFilter>,
+    /// Stops all listeners after accepting the given number of connections.
+    pub max_connections: Option<u64>,
+    /// Initial buffer size of forwarding extra and dirmngr sockets, `DEFAULT_COPY_BUF_SIZE` if
+    /// it's `None`.
+    pub copy_buffer_size: Option<usize>,
+    /// Restarts an agent after forwarding to it fails the given times in a row. It affects
+    /// all clients of the agent, not only the ones of the bridge.
+    pub reconnect_agent_on_error_count: Option<usize>,
+}
+
+struct BridgeCore {
+    agents: parking_lot::Mutex<HashMap<Option<PathBuf>, Arc<Agent>>>,
+    dirmngr: Arc<Agent>,
+    audit_extra: Option<Arc<Auditor>>,
+    bind: BindOptions,
+    pipe_local_namespace: bool,
+    agent_opts: AgentOptions,
+    warmup_retries: usize,
+    agent_startup: AgentStartup,
+    dirmngr_startup: AgentStartup,
+    reload: AtomicBool,
+    listeners: parking_lot::Mutex<HashMap<String, ListenerEntry>>,
+    control: parking_lot::Mutex<Option<Handoff>>,
+    reexec_on_upgrade: bool,
+    framed: bool,
+    framed_correlation: bool,
+    ssh_request_timeout: Option<Duration>,
+    ssh_read_buffer: usize,
+    ssh_log_signs: bool,
+    accept_filter: Option<AcceptFilter>,
+    /// Whether accepted connections are closed instead of forwarded.
+    paused: AtomicBool,
+    max_connections: Option<u64>,
+    reconnect_agent_on_error_count: Option<usize>,
+    copy_buffer_size: usize,
+    idle_timeout: Option<Duration>,
+    /// Connections accepted by listeners, counted against `max_connections`.
+    accepted: AtomicU64,
+    exhausted: Notify,
+    /// Notified whenever a listener is bound.
+    bound: Notify,
+    started: Instant,
+    /// 0 if unknown, 1 if the agent was reachable last time, 2 otherwise.
+    agent_reachable: AtomicU8,
+    upgraded: AtomicBool,
+    upgrade_done: Notify,
+    /// Number of connections being forwarded.
+    active: AtomicUsize,
+    /// Number of connections forwarded since start.
+    connections: AtomicU64,
+    idle: Notify,
+    /// Whether connections being forwarded should be closed, see `Bridge::close_connections`.
+    closing: AtomicBool,
+    close: Notify,
+}
+
+impl BridgeCore {
+    fn delegate_opts(&self) -> DelegateOptions {
+        DelegateOptions {
+            buf_size: self.copy_buffer_size,
+            idle_timeout: self.idle_timeout,
+        }
+    }
+
+    /// Resolves once `Bridge::close_connections` is called.
+    async fn closed(&self) {
+        loop {
+            let close = self.close.notified();
+            if self.closing.load(Ordering::SeqCst) {
+                return;
+            }
+            close.await;
+        }
+    }
+
+    /// Restarts `agent` once it fails `reconnect_agent_on_error_count` times in a row, in
+    /// case it's wedged in a state that reconnecting can't fix.
+    async fn count_agent_failure(&self, agent: &Agent) {
+        let threshold = match self.reconnect_agent_on_error_count {
+            Some(n) if n > 0 => n,
+            _ => return,
+        };
+        if agent.failures.fetch_add(1, Ordering::SeqCst) + 1 < threshold {
+            return;
+        }
+        agent.failures.store(0, Ordering::SeqCst);
+        let home = agent.home.as_deref();
+        warn!(
+            "forwarding to {:?} agent failed {} times in a row, restarting it",
+            agent.ty, threshold
+        );
+        if let Err(e) = kill_agent(agent.ty, home).await {
+            warn!("failed to kill agent: {:?}", e);
+        }
+        if let Err(e) = ping_gpg_agent_with(self.startup(agent.ty), home).await {
+            warn!("failed to start agent: {:?}", e);
+        }
+    }
+
+    /// Gets the command that starts the daemon serving sockets of `ty`.
+    fn startup(&self, ty: SocketType) -> &AgentStartup {
+        match ty {
+            SocketType::Dirmngr => &self.dirmngr_startup,
+            _ => &self.agent_startup,
+        }
+    }
+
+    /// Checks whether connecting to `port` of localhost reaches one of the listeners.
+    fn listens_on(&self, port: u16) -> bool {
+        self.listeners
+            .lock()
+            .keys()
+            .any(|addr| reaches_listener(addr, port))
+    }
+
+    fn set_agent_reachable(&self, reachable: bool) {
+        let v = if reachable { 1 } else { 2 };
+        self.agent_reachable.store(v, Ordering::Relaxed);
+    }
+
+    /// Counts a connection accepted by listeners, returns false if it exceeds
+    /// `max_connections`. Listeners are stopped once the limit is reached.
+    fn count_accepted(&self) -> bool {
+        let max = match self.max_connections {
+            Some(max) => max,
+            None => return true,
+        };
+        let n = self.accepted.fetch_add(1, Ordering::SeqCst) + 1;
+        if n == max {
+            info!("{} connections accepted, stopping listeners", max);
+            for (_, entry) in self.listeners.lock().drain() {
+                entry.stop.notify_one();
+            }
+            self.exhausted.notify_waiters();
+        }
+        n <= max
+    }
+
+    /// Checks whether the connection can be forwarded, refusals are logged.
+    async fn is_accepted(&self, info: &ConnectionInfo) -> bool {
+        let ty = info.ty.short_name();
+        if self.paused.load(Ordering::SeqCst) {
+            info!(
+                "connection {} ({}) is closed: forwarding paused",
+                info.id, ty
+            );
+            return false;
+        }
+        let filter = match &self.accept_filter {
+            Some(f) => f,
+            None => return true,
+        };
+        if filter(info).await {
+            return true;
+        }
+        info!(
+            "connection {} ({}) from {:?} is denied",
+            info.id, ty, info.peer
+        );
+        false
+    }
+}
+
+/// Tracks a connection being forwarded, so it can be drained before exit.
+struct ActiveConnection(Arc<BridgeCore>);
+
+impl ActiveConnection {
+    fn new(core: &Arc<BridgeCore>) -> ActiveConnection {
+        core.active.fetch_add(1, Ordering::SeqCst);
+        core.connections.fetch_add(1, Ordering::Relaxed);
+        ActiveConnection(core.clone())
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        if self.0.active.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+/// How long to wait for the correlation id of a framed connection without `idle_timeout`.
+const CORRELATION_ID_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait for the successor to take over listeners.
+const UPGRADE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Shared states of all listeners that forward requests to gpg-agent.
+///
+/// Listeners served by the same `Bridge` share the agent meta of the same GNUPGHOME, so they
+/// can be added or removed at runtime without reloading the agent.
+#[derive(Clone)]
+pub struct Bridge {
+    core: Arc<BridgeCore>,
+}
+
+impl Bridge {
+    pub fn new(opts: BridgeOptions) -> Bridge {
+        let mut agents = HashMap::new();
+        let mut extra = Agent::new(SocketType::Extra, None, opts.extra_socket);
+        extra.fallbacks = opts
+            .extra_socket_fallbacks
+            .into_iter()
+            .map(|p| Arc::new(Agent::new(SocketType::Extra, None, Some(p))))
+            .collect();
+        agents.insert(None, Arc::new(extra));
+        let dirmngr = Agent::new(SocketType::Dirmngr, None, opts.dirmngr_socket);
+        Bridge {
+            core: Arc::new(BridgeCore {
+                agents: parking_lot::Mutex::new(agents),
+                dirmngr: Arc::new(dirmngr),
+                audit_extra: opts.audit_extra.map(Arc::new),
+                bind: opts.bind,
+                pipe_local_namespace: opts.pipe_local_namespace,
+                agent_opts: AgentOptions {
+                    bind_addr: opts.agent_bind_addr,
+                    fallback_standard: opts.extra_fallback_standard,
+                    failure_cooldown: opts.agent_failure_cooldown,
+                },
+                warmup_retries: opts.warmup_retries.unwrap_or(DEFAULT_WARMUP_RETRIES),
+                agent_startup: opts.agent_startup,
+                dirmngr_startup: opts
+                    .dirmngr_startup
+                    .unwrap_or_else(|| AgentStartup::dirmngr(AgentStartup::default().program)),
+                reload: AtomicBool::new(false),
+                listeners: parking_lot::Mutex::new(HashMap::new()),
+                control: parking_lot::Mutex::new(None),
+                reexec_on_upgrade: opts.reexec_on_upgrade,
+                framed: opts.framed,
+                framed_correlation: opts.framed_correlation,
+                ssh_request_timeout: opts.ssh_request_timeout,
+                ssh_read_buffer: opts.ssh_read_buffer.unwrap_or(DEFAULT_SSH_READ_BUFFER),
+                ssh_log_signs: opts.ssh_log_signs,
+                accept_filter: opts.accept_filter,
+                upgraded: AtomicBool::new(false),
+                upgrade_done: Notify::new(),
+                active: AtomicUsize::new(0),
+                connections: AtomicU64::new(0),
+                idle: Notify::new(),
+                closing: AtomicBool::new(false),
+                close: Notify::new(),
+                paused: AtomicBool::new(false),
+                max_connections: opts.max_connections,
+                reconnect_agent_on_error_count: opts.reconnect_agent_on_error_count,
+                idle_timeout: opts.idle_timeout,
+                copy_buffer_size: opts
+                    .copy_buffer_size
+                    .unwrap_or(DEFAULT_COPY_BUF_SIZE)
+                    .max(1),
+                accepted: AtomicU64::new(0),
+                exhausted: Notify::new(),
+                bound: Notify::new(),
+                started: Instant::now(),
+                agent_reachable: AtomicU8::new(0),
+            }),
+        }
+    }
+
+    /// Refuses to listen on `from_addr` if it's where the agent of `ty` listens.
+    ///
+    /// Only the agents that have been started are checked, others are checked when
+    /// connecting.
+    async fn check_forwarding_loop(
+        &self,
+        ty: SocketType,
+        from_addr: &str,
+        home: &Option<PathBuf>,
+    ) -> io::Result<()> {
+        let agent = match ty {
+            SocketType::Extra if home.is_none() => self.agent(None),
+            SocketType::Dirmngr => self.core.dirmngr.clone(),
+            _ => return Ok(()),
+        };
+        let mut m = agent.meta.lock().await;
+        if m.target.is_none() {
+            let fallback = self.core.agent_opts.fallback_standard;
+            let startup = self.core.startup(agent.ty);
+            match resolve_agent_target(&mut m, agent.ty, startup, None, fallback).await {
+                Ok(target) => m.target = Some(target),
+                Err(e) => {
+                    debug!("skip checking {} for forwarding loop: {:?}", from_addr, e);
+                    return Ok(());
+                }
+            }
+        }
+        let port = m.target.as_ref().unwrap().port;
+        if reaches_listener(from_addr, port) {
+            return Err(forwarding_loop_error(port));
+        }
+        Ok(())
+    }
+
+    async fn bind(
+        &self,
+        ty: SocketType,
+        from_addr: &str,
+        home: &Option<PathBuf>,
+        stop: &Arc<Notify>,
+    ) -> io::Result<AnyListener> {
+        self.check_forwarding_loop(ty, from_addr, home).await?;
+        let listener = self.bind_addr(from_addr).await?;
+        if let Some(entry) = self.core.listeners.lock().get_mut(from_addr) {
+            if Arc::ptr_eq(&entry.stop, stop) {
+                let addr = match listener {
+                    AnyListener::Pipe(_) if self.core.pipe_local_namespace => {
+                        local_pipe_addr(from_addr)?
+                    }
+                    _ => from_addr.to_owned(),
+                };
+                entry.handoff = Some(Handoff::new(&addr, &listener));
+                self.core.bound.notify_waiters();
+            }
+        }
+        Ok(listener)
+    }
+
+    async fn bind_addr(&self, from_addr: &str) -> io::Result<AnyListener> {
+        if !is_pipe_addr(from_addr) {
+            return bind(from_addr, &self.core.bind).await;
+        }
+        let local_addr;
+        let mut addr = from_addr;
+        let mut opts = self.core.bind.clone();
+        if self.core.pipe_local_namespace {
+            local_addr = local_pipe_addr(from_addr)?;
+            info!("{} is bound as {}", from_addr, local_addr);
+            addr = &local_addr;
+            // The name alone doesn't keep other sessions out.
+            opts.pipe_session_only = true;
+        }
+        if !self.core.bind.pipe_reject_remote {
+            warn!("{} accepts clients from other machines", addr);
+        }
+        if !self.core.bind.first_pipe_instance {
+            warn!(
+                "{} may join a pipe created by others, make sure it's trusted",
+                addr
+            );
+        }
+        bind(addr, &opts).await
+    }
+
+    fn agent(&self, home: Option<PathBuf>) -> Arc<Agent> {
+        let mut agents = self.core.agents.lock();
+        agents
+            .entry(home.clone())
+            .or_insert_with(|| Arc::new(Agent::new(SocketType::Extra, home, None)))
+            .clone()
+    }
+
+    fn register(
+        &self,
+        ty: SocketType,
+        from_addr: &str,
+        home: &Option<PathBuf>,
+    ) -> io::Result<Arc<Notify>> {
+        if ty != SocketType::Extra && home.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "GNUPGHOME can only be specified for extra socket",
+            ));
+        }
+        let mut listeners = self.core.listeners.lock();
+        if listeners.contains_key(from_addr) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} is already listened", from_addr),
+            ));
+        }
+        let stop = Arc::new(Notify::new());
+        let entry = ListenerEntry {
+            ty,
+            home: home.clone(),
+            stop: stop.clone(),
+            handoff: None,
+        };
+        listeners.insert(from_addr.to_owned(), entry);
+        Ok(stop)
+    }
+
+    fn unregister(&self, from_addr: &str, stop: &Arc<Notify>) {
+        let mut listeners = self.core.listeners.lock();
+        // The address may be removed and added again in the meantime.
+        if listeners
+            .get(from_addr)
+            .is_some_and(|e| Arc::ptr_eq(&e.stop, stop))
+        {
+            listeners.remove(from_addr);
+        }
+    }
+
+    /// Forwards all requests from `from_addr` to the socket of `ty`.
+    ///
+    /// `from_addr` can be either TCP address or Named Pipe. Requests to extra socket are
+    /// forwarded to the agent of `home` if specified. It returns when the listener fails or
+    /// is removed by `remove_listener`.
+    pub async fn serve(
+        &self,
+        ty: SocketType,
+        from_addr: String,
+        home: Option<PathBuf>,
+    ) -> io::Result<()> {
+        self.serve_in(ty, from_addr, home, false).await
+    }
+
+    /// Same as `serve` with extra socket, but only `GETINFO`, `NOP` and `BYE` are forwarded,
+    /// other commands are answered by `ERR` without reaching agent.
+    ///
+    /// It's meant for health checks that should not be able to use any key.
+    pub async fn serve_probe(&self, from_addr: String) -> io::Result<()> {
+        self.serve_in(SocketType::Extra, from_addr, None, true)
+            .await
+    }
+
+    async fn serve_in(
+        &self,
+        ty: SocketType,
+        from_addr: String,
+        home: Option<PathBuf>,
+        probe_only: bool,
+    ) -> io::Result<()> {
+        let stop = self.register(ty, &from_addr, &home)?;
+        let res = match self.bind(ty, &from_addr, &home, &stop).await {
+            Ok(listener) => {
+                let mode = self.stream_mode(ty, &listener, probe_only);
+                self.bridge_listener(ty, listener, home, mode, &stop).await
+            }
+            Err(e) => Err(e),
+        };
+        self.unregister(&from_addr, &stop);
+        res
+    }
+
+    /// Starts forwarding requests from `from_addr` in background.
+    ///
+    /// Unlike `serve`, it returns as soon as the address is bound. Errors after that are
+    /// only logged.
+    pub async fn add_listener(
+        &self,
+        ty: SocketType,
+        from_addr: String,
+        home: Option<PathBuf>,
+    ) -> io::Result<()> {
+        let stop = self.register(ty, &from_addr, &home)?;
+        let listener = match self.bind(ty, &from_addr, &home, &stop).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                self.unregister(&from_addr, &stop);
+                return Err(e);
+            }
+        };
+        let bridge = self.clone();
+        let mode = self.stream_mode(ty, &listener, false);
+        tokio::spawn(async move {
+            if let Err(e) = bridge
+                .bridge_listener(ty, listener, home, mode, &stop)
+                .await
+            {
+                error!("failed to serve {}: {:?}", from_addr, e);
+            }
+            bridge.unregister(&from_addr, &stop);
+        });
+        Ok(())
+    }
+
+    /// Stops accepting new connections from `from_addr`.
+    ///
+    /// Connections that have been accepted are not affected.
+    pub fn remove_listener(&self, from_addr: &str) -> io::Result<()> {
+        match self.core.listeners.lock().remove(from_addr) {
+            Some(entry) => {
+                entry.stop.notify_one();
+                Ok(())
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} is not listened", from_addr),
+            )),
+        }
+    }
+
+    /// Stops accepting new connections from all listeners.
+    pub fn shutdown(&self) {
+        for (_, entry) in self.core.listeners.lock().drain() {
+            entry.stop.notify_one();
+        }
+    }
+
+    /// Records the control listener, so it's handed off by `upgrade` as well.
+    pub(crate) fn set_control(&self, addr: &str, listener: &AnyListener) {
+        *self.core.control.lock() = Some(Handoff::new(addr, listener));
+    }
+
+    /// Hands off all listeners to a new process running the current executable.
+    ///
+    /// The new process gets the same arguments, and TCP listeners are passed via handle
+    /// inheritance as described in `INHERIT_ENV`. Once it replies `UPGRADE_READY`, this
+    /// bridge stops accepting, and `upgraded` resolves so accepted connections can be
+    /// drained by `drain`.
+    pub async fn upgrade(&self) -> io::Result<()> {
+        if !self.core.reexec_on_upgrade {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "upgrade is not enabled",
+            ));
+        }
+        let mut handoffs: Vec<_> = self
+            .core
+            .listeners
+            .lock()
+            .values()
+            .filter_map(|e| e.handoff.clone())
+            .collect();
+        if handoffs.is_empty() {
+            return Err(other_error("no listener to hand off".to_string()));
+        }
+        handoffs.extend(self.core.control.lock().clone());
+        let mut list = String::new();
+        for h in &handoffs {
+            match h.socket {
+                Some(socket) => list.push_str(&format!("{} {}\n", h.addr, socket)),
+                None => list.push_str(&format!("{} pipe\n", h.addr)),
+            }
+        }
+        let sockets: Vec<_> = handoffs.iter().filter_map(|h| h.socket).collect();
+        let spawned = sockets
+            .iter()
+            .try_for_each(|s| set_inheritable(*s, true))
+            .and_then(|_| {
+                Command::new(env::current_exe()?)
+                    .args(env::args_os().skip(1))
+                    .env(INHERIT_ENV, list)
+                    .stdout(Stdio::piped())
+                    .spawn()
+            });
+        // Only the successor should inherit the listeners, whether it's spawned or not.
+        // Processes spawned later, like gpg-connect-agent, would keep them open otherwise.
+        for s in &sockets {
+            if let Err(e) = set_inheritable(*s, false) {
+                warn!("{}", e);
+            }
+        }
+        let mut child = spawned?;
+        let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+        match time::timeout(UPGRADE_TIMEOUT, lines.next_line()).await {
+            Ok(Ok(Some(line))) if line == UPGRADE_READY => (),
+            res => {
+                let _ = child.kill().await;
+                return Err(other_error(format!(
+                    "new process fails to take over: {:?}",
+                    res
+                )));
+            }
+        }
+        info!("listeners are handed off to process {:?}", child.id());
+        self.shutdown();
+        self.core.upgraded.store(true, Ordering::SeqCst);
+        self.core.upgrade_done.notify_waiters();
+        Ok(())
+    }
+
+    /// Resolves after `upgrade` succeeds.
+    pub async fn upgraded(&self) {
+        let notified = self.core.upgrade_done.notified();
+        if !self.is_upgraded() {
+            notified.await;
+        }
+    }
+
+    pub fn is_upgraded(&self) -> bool {
+        self.core.upgraded.load(Ordering::SeqCst)
+    }
+
+    /// Resolves after listeners accept `BridgeOptions::max_connections` connections.
+    pub async fn exhausted(&self) {
+        let notified = self.core.exhausted.notified();
+        if !self.is_exhausted() {
+            notified.await;
+        }
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        self.core
+            .max_connections
+            .is_some_and(|max| self.core.accepted.load(Ordering::SeqCst) >= max)
+    }
+
+    /// Closes new connections instead of forwarding them until `resume` is called.
+    ///
+    /// Listeners keep accepting, and connections that are being forwarded are not affected.
+    pub fn pause(&self) {
+        self.core.paused.store(true, Ordering::SeqCst);
+        info!("forwarding paused");
+    }
+
+    pub fn resume(&self) {
+        self.core.paused.store(false, Ordering::SeqCst);
+        info!("forwarding resumed");
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.core.paused.load(Ordering::SeqCst)
+    }
+
+    /// Closes all connections being forwarded and those accepted later, which are logged
+    /// as closed by `shutdown`.
+    pub fn close_connections(&self) {
+        self.core.closing.store(true, Ordering::SeqCst);
+        self.core.close.notify_waiters();
+    }
+
+    /// Waits until all accepted connections are closed.
+    pub async fn drain(&self) {
+        loop {
+            let idle = self.core.idle.notified();
+            if self.core.active.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            idle.await;
+        }
+    }
+
+    /// Lists the keys offered by the ssh agent.
+    pub async fn ssh_identities(&self) -> io::Result<Vec<Identity>> {
+        ssh::request_identities().await
+    }
+
+    /// Lists keygrips of the keys known by the agent of `home` via extra socket.
+    pub async fn extra_keygrips(&self, home: Option<PathBuf>) -> io::Result<Vec<String>> {
+        let agent = self.agent(home);
+        let stream = open_agent(&agent, &self.core).await?;
+        list_keygrips(stream).await
+    }
+
+    /// Forgets ports and nonces of used agents, and loads them again after pinging, as
+    /// agents may be restarted while the system sleeps.
+    async fn refresh_agents(&self) {
+        self.core.reload.store(true, Ordering::SeqCst);
+        let mut agents: Vec<_> = self.core.agents.lock().values().cloned().collect();
+        agents.push(self.core.dirmngr.clone());
+        let fallback = self.core.agent_opts.fallback_standard;
+        for agent in agents {
+            if !agent.warmed_up.load(Ordering::SeqCst) {
+                continue;
+            }
+            let home = agent.home.as_deref();
+            let mut m = agent.meta.lock().await;
+            m.target = None;
+            m.down = None;
+            let startup = self.core.startup(agent.ty);
+            let _ = ping_gpg_agent_with(startup, home).await;
+            match resolve_agent_target(&mut m, agent.ty, startup, home, fallback).await {
+                Ok(target) => m.target = Some(target),
+                // It's loaded again by next connection.
+                Err(e) => debug!("failed to refresh {:?}: {:?}", agent.ty, e),
+            }
+        }
+    }
+
+    /// Refreshes agents every time the system resumes from sleep.
+    pub fn watch_resume(&self) -> io::Result<()> {
+        let mut events = power::resume_events()?;
+        let bridge = self.clone();
+        tokio::spawn(async move {
+            while events.recv().await.is_some() {
+                info!("system resumed, refreshing agents");
+                bridge.refresh_agents().await;
+            }
+        });
+        Ok(())
+    }
+
+    /// Gets aggregate counters of forwarded connections.
+    pub fn stats(&self) -> Stats {
+        Stats {
+            connections: self.core.connections.load(Ordering::Relaxed),
+            active: self.core.active.load(Ordering::SeqCst),
+            received: BYTES_RECEIVED.load(Ordering::Relaxed),
+            replied: BYTES_REPLIED.load(Ordering::Relaxed),
+            uptime: self.core.started.elapsed(),
+            agent_reachable: match self.core.agent_reachable.load(Ordering::Relaxed) {
+                0 => None,
+                v => Some(v == 1),
+            },
+        }
+    }
+
+    /// Gets all active listeners.
+    pub fn listeners(&self) -> Vec<ListenerInfo> {
+        self.core
+            .listeners
+            .lock()
+            .iter()
+            .map(|(addr, e)| ListenerInfo {
+                ty: e.ty,
+                addr: addr.clone(),
+                gnupghome: e.home.clone(),
+            })
+            .collect()
+    }
+
+    /// Waits until at least `count` listeners are bound, and returns the bound ones with
+    /// the addresses that are actually listened on.
+    pub async fn bound_listeners(&self, count: usize) -> Vec<ListenerInfo> {
+        loop {
+            // Created before checking so a bind in between is not missed.
+            let bound = self.core.bound.notified();
+            let listeners: Vec<_> = self
+                .core
+                .listeners
+                .lock()
+                .values()
+                .filter_map(|e| {
+                    e.handoff.as_ref().map(|h| ListenerInfo {
+                        ty: e.ty,
+                        addr: h.addr.clone(),
+                        gnupghome: e.home.clone(),
+                    })
+                })
+                .collect();
+            if listeners.len() >= count {
+                return listeners;
+            }
+            bound.await;
+        }
+    }
+
+    /// Frames are only used between peers over TCP, local pipes and sockets are reliable.
+    fn stream_mode(&self, ty: SocketType, listener: &AnyListener, probe_only: bool) -> StreamMode {
+        if probe_only {
+            StreamMode::ProbeOnly
+        } else if self.core.framed
+            && ty == SocketType::Extra
+            && matches!(listener, AnyListener::Tcp(_))
+        {
+            StreamMode::Framed
+        } else {
+            StreamMode::Plain
+        }
+    }
+
+    async fn bridge_listener<L>(
+        &self,
+        ty: SocketType,
+        listener: L,
+        home: Option<PathBuf>,
+        mode: StreamMode,
+        stop: &Notify,
+    ) -> io::Result<()>
+    where
+        L: Listener,
+        L::Connection: SplitStream + Send + 'static,
+    {
+        match ty {
+            SocketType::Extra => {
+                let agent = self.agent(home);
+                self.bridge_to_stream(listener, agent, mode, stop).await?
+            }
+            SocketType::Dirmngr => {
+                let agent = self.core.dirmngr.clone();
+                self.bridge_to_stream(listener, agent, mode, stop).await?
+            }
+            SocketType::Ssh => self.bridge_to_message(listener, stop).await?,
+        }
+        Ok(())
+    }
+
+    async fn bridge_to_stream<L>(
+        &self,
+        mut listener: L,
+        agent: Arc<Agent>,
+        mode: StreamMode,
+        stop: &Notify,
+    ) -> io::Result<()>
+    where
+        L: Listener,
+        L::Connection: SplitStream + Send + 'static,
+    {
+        loop {
+            let conn = tokio::select! {
+                conn = listener.accept() => conn?,
+                _ = stop.notified() => return Ok(()),
+            };
+            if !self.core.count_accepted() {
+                continue;
+            }
+
+            let forward = self.forward_stream(conn, agent.clone(), mode);
+            tokio::spawn(async move {
+                if let Err(e) = forward.await {
+                    error!("failed to delegate stream: {:?}", e);
+                }
+            });
+        }
+    }
+
+    /// Forwards `conn` accepted by embedders to extra socket of the agent of `home`.
+    ///
+    /// Together with `AnyListener::incoming`, it allows accepting connections in a custom
+    /// loop instead of `serve`.
+    pub async fn forward_extra<C>(&self, conn: C, home: Option<PathBuf>) -> io::Result<()>
+    where
+        C: SplitStream + Send + 'static,
+    {
+        let agent = self.agent(home);
+        self.forward_stream(conn, agent, StreamMode::Plain).await
+    }
+
+    /// Prepares forwarding at the time `conn` is accepted, the returned future can be spawned.
+    fn forward_stream<C>(
+        &self,
+        conn: C,
+        agent: Arc<Agent>,
+        mode: StreamMode,
+    ) -> impl Future<Output = io::Result<()>> + Send + 'static
+    where
+        C: SplitStream + Send + 'static,
+    {
+        let id = next_connection_id();
+        let audit = match agent.ty {
+            SocketType::Extra => self.core.audit_extra.as_ref().map(|a| a.session(id)),
+            _ => None,
+        };
+        // Agent may be still starting up when the first client comes, retry to smooth
+        // over the race.
+        let retries = if agent.warmed_up.swap(true, Ordering::SeqCst) {
+            0
+        } else {
+            self.core.warmup_retries
+        };
+        let info = ConnectionInfo {
+            id,
+            ty: agent.ty,
+            peer: conn.peer(),
+        };
+        let core = self.core.clone();
+        let active = ActiveConnection::new(&self.core);
+        async move {
+            let _active = active;
+            if !core.is_accepted(&info).await {
+                return Ok(());
+            }
+            let mut conn = conn;
+            if mode == StreamMode::Framed && core.framed_correlation {
+                // A client that never sends the id should not hold the connection forever.
+                let timeout = core.idle_timeout.unwrap_or(CORRELATION_ID_TIMEOUT);
+                let cid = time::timeout(timeout, framed::read_correlation_id(&mut conn))
+                    .await
+                    .map_err(|_| {
+                        io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            format!("no correlation id is received in {:?}", timeout),
+                        )
+                    })??;
+                info!("connection {} is correlated as {}", id, cid);
+            }
+            let start = Instant::now();
+            let to = open_agent_with_failover(&agent, &core, retries).await;
+            let opened = start.elapsed();
+            let open_failed = to.is_err();
+            if agent.ty == SocketType::Extra {
+                core.set_agent_reachable(to.is_ok());
+            }
+            let res = match (to, mode) {
+                (Ok(to), StreamMode::Plain) => {
+                    let (opts, stop) = (core.delegate_opts(), core.closed());
+                    delegate(id, agent.ty, conn, to, audit, opts, stop).await
+                }
+                (Ok(to), StreamMode::Framed) => {
+                    let (opts, stop) = (core.delegate_opts(), core.closed());
+                    delegate(id, agent.ty, Framed(conn), to, audit, opts, stop).await
+                }
+                (Ok(to), StreamMode::ProbeOnly) => probe::delegate_probe(id, conn, to).await,
+                (Err(e), _) => Err(e),
+            };
+            if trace_timing() {
+                debug!(
+                    "connection {}: agent opened in {:?}, transferred in {:?}",
+                    id,
+                    opened,
+                    start.elapsed() - opened
+                );
+            }
+            match &res {
+                Err(e) => {
+                    // The socket file may be stale, reload it on next connection.
+                    agent.meta.lock().await.target.take();
+                    // Errors of clients, including idle ones, are not the agent's fault.
+                    if open_failed || AgentSideError::is(e) {
+                        core.count_agent_failure(&agent).await;
+                    }
+                }
+                Ok(()) => agent.failures.store(0, Ordering::SeqCst),
+            }
+            res
+        }
+    }
+
+    async fn bridge_to_message<L>(&self, mut listener: L, stop: &Notify) -> io::Result<()>
+    where
+        L: Listener,
+        L::Connection: SplitStream + Send + 'static,
+    {
+        loop {
+            let conn = tokio::select! {
+                conn = listener.accept() => conn?,
+                _ = stop.notified() => return Ok(()),
+            };
+            if !self.core.count_accepted() {
+                continue;
+            }
+
+            let bridge = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = bridge.forward_ssh(conn).await {
+                    error!("failed to delegate message: {:?}", e);
+                }
+            });
+        }
+    }
+
+    /// Forwards `conn` accepted by embedders to ssh agent.
+    pub async fn forward_ssh(&self, conn: impl SplitStream) -> io::Result<()> {
+        let _active = ActiveConnection::new(&self.core);
+        let info = ConnectionInfo {
+            id: next_connection_id(),
+            ty: SocketType::Ssh,
+            peer: conn.peer(),
+        };
+        if !self.core.is_accepted(&info).await {
+            return Ok(());
+        }
+        if self.core.reload.load(Ordering::SeqCst) {
+            ping_gpg_agent_with(&self.core.agent_startup, None).await?;
+            self.core.reload.store(false, Ordering::SeqCst);
+        }
+        let timeout = self.core.ssh_request_timeout;
+        let buffer = self.core.ssh_read_buffer;
+        let log_signs = self.core.ssh_log_signs;
+        let idle = self.core.idle_timeout;
+        let stop = self.core.closed();
+        let res = delegate_ssh(info.id, conn, timeout, buffer, log_signs, idle, stop).await;
+        if res.is_err() {
+            self.core.reload.store(true, Ordering::SeqCst);
+        }
+        res
+    }
+}
+
+/// A bridge that forwards all requests from certain stream to gpg-agent on Windows.
+///
+/// `to_path` should point to the path of gnupg UDS. `from_addr` can be either TCP address
+/// or Named Pipe.
+pub async fn bridge(ty: SocketType, from_addr: String, to_path: Option<PathBuf>) -> io::Result<()> {
+    bridge_in(ty, from_addr, to_path, None).await
+}
+
+/// Same as `bridge`, but forwards requests of extra socket to the agent of `home`.
+///
+/// Only the agent of `home` is started, the default one is left untouched.
+pub async fn bridge_in(
+    ty: SocketType,
+    from_addr: String,
+    to_path: Option<PathBuf>,
+    home: Option<PathBuf>,
+) -> io::Result<()> {
+    let mut opts = BridgeOptions::default();
+    match ty {
+        SocketType::Dirmngr => opts.dirmngr_socket = to_path,
+        _ => opts.extra_socket = to_path,
+    }
+    let bridge = Bridge::new(opts);
+    // Attempt to setup gpg-agent if it's not up yet.
+    let _ = ping_gpg_agent_with(bridge.core.startup(ty), home.as_deref()).await;
+    bridge.serve(ty, from_addr, home).await
+}
+
+fn unexpected_reply(line: &str) -> io::Error {
+    other_error(format!("unexpected reply from agent: {:?}", line))
+}
+
+/// Sends `KEYINFO --list` to agent and collects keygrips from the status lines.
+async fn list_keygrips(mut stream: TcpStream) -> io::Result<Vec<String>> {
+    let (reader, mut writer) = stream.split();
+    let mut lines = BufReader::new(reader).lines();
+    match lines.next_line().await? {
+        Some(greeting) if greeting.starts_with("OK") => (),
+        Some(line) => return Err(unexpected_reply(&line)),
+        None => return Err(io::ErrorKind::UnexpectedEof.into()),
+    }
+    writer.write_all(b"KEYINFO --list\n").await?;
+    let mut keygrips = Vec::new();
+    while let Some(line) = lines.next_line().await? {
+        if let Some(info) = line.strip_prefix("S KEYINFO ") {
+            if let Some(keygrip) = info.split(' ').next() {
+                keygrips.push(keygrip.to_owned());
+            }
+        } else if line == "OK" || line.starts_with("OK ") {
+            return Ok(keygrips);
+        } else if line.starts_with("ERR") {
+            return Err(other_error(format!("agent replied {}", line)));
+        } else if !line.starts_with('#') {
+            return Err(unexpected_reply(&line));
+        }
+    }
+    Err(io::ErrorKind::UnexpectedEof.into())
+}
+
+// For now, forwarding ssh agent requests can only be done using IPC messages. gpg
+// ssh agent seems to do security trick on tcp stream and fail to receive anything.
+async fn delegate_ssh(
+    id: u64,
+    mut from: impl SplitStream,
+    timeout: Option<Duration>,
+    buffer: usize,
+    log_signs: bool,
+    idle_timeout: Option<Duration>,
+    stop: impl Future<Output = ()>,
+) -> io::Result<()> {
+    let (mut source_read, mut source_write) = from.split_rw();
+    if buffer > 0 {
+        // Length prefix and body are read separately, which are usually sent together.
+        source_read = Box::pin(ScrubReader::with_capacity(buffer, source_read));
+    }
+    let mut handler = ssh::Handler::new(timeout).await?;
+    handler.set_idle_timeout(idle_timeout);
+    if log_signs {
+        handler.log_signs(id);
+    }
+    let work = async {
+        while let Some(resp) = handler.process_one(&mut source_read).await? {
+            trace!("get {}", loggable(resp));
+            let start = Instant::now();
+            source_write.write_all(resp).await?;
+            if trace_timing() {
+                let (read, agent) = handler.last_timing();
+                debug!(
+                    "connection {}: request read in {:?}, agent replied in {:?}, written in {:?}",
+                    id,
+                    read,
+                    agent,
+                    start.elapsed()
+                );
+            }
+        }
+        io::Result::Ok(())
+    };
+    let (res, reason) = tokio::select! {
+        res = work => match res {
+            Ok(()) => (Ok(()), CloseReason::ClientEof),
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => (Err(e), CloseReason::Timeout),
+            Err(e) => (Err(e), CloseReason::Error),
+        },
+        _ = stop => (Ok(()), CloseReason::Shutdown),
+    };
+    if let Err(e) = &res {
+        if ssh::IdleTimeout::is(e) {
+            // No request is waiting for reply, anything written would break the framing.
+            let _ = source_write.shutdown().await;
+        } else if ssh::FrameTooLarge::is(e) || e.kind() == io::ErrorKind::TimedOut {
+            // Let client fail cleanly instead of waiting for a reply that will never come.
+            let _ = source_write.write_all(&ssh::SSH_AGENT_FAILURE_FRAME).await;
+        }
+    }
+    add_bytes(handler.received() as u64, handler.replied() as u64);
+    debug!(
+        "connection {} ({}) closed: {}, received {}, replied {}",
+        id,
+        SocketType::Ssh.short_name(),
+        reason,
+        handler.received(),
+        handler.replied()
+    );
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, process};
+
+    /// Creates an empty directory for test `name`.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("gpg-bridge-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Writes a socket file in `SocketFormat::Plain` for `port` and `nonce`.
+    fn write_plain_socket(path: &Path, port: u16, nonce: [u8; 16]) {
+        let mut content = format!("{}\n", port).into_bytes();
+        content.extend_from_slice(&nonce);
+        fs::write(path, content).unwrap();
+    }
+
+    /// A startup command that creates `marker` instead of starting an agent.
+    fn marker_startup(marker: &Path) -> AgentStartup {
+        AgentStartup {
+            program: PathBuf::from("cmd"),
+            args: vec![
+                "/C".to_owned(),
+                "type".to_owned(),
+                "nul".to_owned(),
+                ">".to_owned(),
+                marker.display().to_string(),
+            ],
+        }
+    }
+
+    fn utf16(s: &str, bom: bool) -> Vec<u8> {
+        let bom = Some(0xfeff).filter(|_| bom);
+        bom.into_iter()
+            .chain(s.encode_utf16())
+            .flat_map(u16::to_le_bytes)
+            .collect()
+    }
+
+    #[test]
+    fn test_decode_output() {
+        let path = r"C:\Users\me\AppData\Roaming\gnupg";
+        let non_ascii = r"C:\Users\José\文档\gnupg";
+        for s in [path, non_ascii] {
+            assert_eq!(decode_output(s.as_bytes().to_vec()).unwrap(), s);
+            let mut with_bom = "\u{feff}".as_bytes().to_vec();
+            with_bom.extend_from_slice(s.as_bytes());
+            assert_eq!(decode_output(with_bom).unwrap(), s);
+            assert_eq!(decode_output(utf16(s, true)).unwrap(), s);
+            assert_eq!(decode_output(utf16(s, false)).unwrap(), s);
+        }
+        // ASCII in UTF-16 without BOM is valid UTF-8 as well.
+        assert!(str::from_utf8(&utf16(path, false)).is_ok());
+        assert_eq!(decode_output(utf16("a\r\n", false)).unwrap(), "a\r\n");
+
+        assert_eq!(decode_output(vec![]).unwrap(), "");
+        // Truncated UTF-16.
+        let mut truncated = utf16(path, true);
+        truncated.pop();
+        assert!(decode_output(truncated).is_err());
+    }
+
+    #[test]
+    fn test_load_cygwin_port_nounce() {
+        let (port, nonce) =
+            load_cygwin_port_nounce(b"4321 s 01234567-89abcdef-00000000-ffffffff\0").unwrap();
+        assert_eq!(port, 4321);
+        let expected: Vec<u8> = [0x01234567u32, 0x89abcdef, 0, 0xffffffff]
+            .iter()
+            .flat_map(|n| n.to_ne_bytes())
+            .collect();
+        assert_eq!(nonce[..], expected[..]);
+        let (port, _) =
+            load_cygwin_port_nounce(b"65535 s 00000000-00000000-00000000-00000000\0").unwrap();
+        assert_eq!(port, 65535);
+
+        for content in [
+            &b"0 s 01234567-89abcdef-00000000-ffffffff\0"[..],
+            b"65536 s 01234567-89abcdef-00000000-ffffffff\0",
+            b"4321 s 01234567-89abcdef-00000000-ffffffff",
+            b"4321 s 01234567-89abcdef-00000000-ffffffffx",
+            b"4321 s 0123-4567-89ab-cdef-0000-0000-ffff\0",
+            b"4321 s +1234567-89abcdef-00000000-ffffffff\0",
+            b"4321 s 01234567 89abcdef 00000000 ffffffff\0",
+            b"4321s 01234567-89abcdef-00000000-ffffffff\0",
+            b"4321 ",
+            b"",
+        ] {
+            let e = load_cygwin_port_nounce(content).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData, "{:?}", content);
+        }
+    }
+
+    /// A source of content starting with "fake", which always loads port 1234.
+    struct FakeNonce;
+
+    impl NonceSource for FakeNonce {
+        fn format(&self) -> SocketFormat {
+            SocketFormat::Cygwin
+        }
+
+        fn matches(&self, content: &[u8]) -> bool {
+            content.starts_with(b"fake")
+        }
+
+        fn load(&self, _: &[u8]) -> io::Result<AgentTarget> {
+            Ok(AgentTarget {
+                port: 1234,
+                handshake: None,
+            })
+        }
+    }
+
+    #[test]
+    fn test_parse_socket_content() {
+        let cygwin = &b"!<socket >4321 s 01234567-89abcdef-00000000-ffffffff\0"[..];
+        let mut plain = b"4321\n".to_vec();
+        plain.extend_from_slice(&[0x5a; 16]);
+        for (content, format) in [
+            (cygwin, SocketFormat::Cygwin),
+            (&plain, SocketFormat::Plain),
+        ] {
+            let (f, target) = parse_socket_content(NONCE_SOURCES, content).unwrap();
+            assert_eq!(f, format);
+            assert_eq!(target.port, 4321);
+            assert_eq!(target.handshake.unwrap().len(), 16);
+        }
+
+        // The first matching source is used.
+        let fake = &b"fake socket with a long content"[..];
+        let sources: [&dyn NonceSource; 2] = [&FakeNonce, &PlainNonce];
+        let (_, target) = parse_socket_content(&sources, fake).unwrap();
+        assert_eq!((target.port, target.handshake), (1234, None));
+        let (_, target) = parse_socket_content(&sources, &plain).unwrap();
+        assert_eq!(target.port, 4321);
+        // Plain format matches any long content, so the fake one is never reached.
+        let sources: [&dyn NonceSource; 2] = [&PlainNonce, &FakeNonce];
+        let e = parse_socket_content(&sources, fake).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        for sources in [&[][..], NONCE_SOURCES] {
+            let e = parse_socket_content(sources, b"short").err().unwrap();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_socket_path_with_spaces_and_non_ascii() {
+        let base = temp_dir("path with spaces");
+        let dir = base.join("José 文档").join("gnupg");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("S.gpg-agent.extra");
+        write_plain_socket(&path, 4321, [0x5a; 16]);
+        let startup = AgentStartup::default();
+        let info = load_socket_info(&path, &startup, None).await.unwrap();
+        assert_eq!(info.paths, [path]);
+        assert_eq!(info.format, SocketFormat::Plain);
+        assert_eq!(info.target.port, 4321);
+        assert_eq!(info.target.handshake.unwrap(), [0x5a; 16]);
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_socket_redirection() {
+        let dir = temp_dir("redirection");
+        let (redirect, target) = (dir.join("S.gpg-agent.extra"), dir.join("real"));
+        let content = format!("%Assuan%\nsocket={}\n", target.display());
+        fs::write(&redirect, content).unwrap();
+        write_plain_socket(&target, 4321, [0x5a; 16]);
+        let startup = AgentStartup::default();
+        let info = load_socket_info(&redirect, &startup, None).await.unwrap();
+        assert_eq!(info.paths, [redirect.clone(), target]);
+        assert_eq!(info.target.port, 4321);
+        assert_eq!(info.target.handshake.unwrap(), [0x5a; 16]);
+
+        // Redirecting to itself is a loop.
+        let content = format!("%Assuan%\nsocket={}\n", redirect.display());
+        fs::write(&redirect, content).unwrap();
+        let e = load_socket_info(&redirect, &startup, None)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn meta(path: Option<PathBuf>) -> AgentMeta {
+        AgentMeta {
+            path,
+            discovered: false,
+            target: None,
+            down: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_extra_socket_precedence() {
+        let dir = temp_dir("extra-socket-env");
+        let (pinned, configured) = (dir.join("pinned"), dir.join("configured"));
+        write_plain_socket(&pinned, 1111, [0x5a; 16]);
+        write_plain_socket(&configured, 2222, [0x5a; 16]);
+        PINNED_EXTRA_SOCKET.with(|p| *p.borrow_mut() = Some(pinned.clone().into()));
+        let startup = AgentStartup::default();
+        let ty = SocketType::Extra;
+
+        // Configured path goes first.
+        let mut m = meta(Some(configured));
+        let target = resolve_agent_target(&mut m, ty, &startup, None, false).await;
+        assert_eq!(target.unwrap().port, 2222);
+        // Then the environment variable, before gpgconf.
+        let mut m = meta(None);
+        let target = resolve_agent_target(&mut m, ty, &startup, None, false).await;
+        assert_eq!(target.unwrap().port, 1111);
+        assert_eq!(m.path.as_ref(), Some(&pinned));
+        // Other homes and sockets are still discovered by gpgconf.
+        let path = load_gpg_socket_path(ty, Some(&dir)).await.ok();
+        assert_ne!(path.as_ref(), Some(&pinned));
+        let path = load_gpg_socket_path(SocketType::Ssh, None).await.ok();
+        assert_ne!(path.as_ref(), Some(&pinned));
+
+        PINNED_EXTRA_SOCKET.with(|p| p.borrow_mut().take());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_zero_port_or_nonce() {
+        let dir = temp_dir("zero-nonce");
+        let (path, marker) = (dir.join("S.gpg-agent.extra"), dir.join("started"));
+        let startup = marker_startup(&marker);
+        for (port, nonce) in [(4321, [0; 16]), (0, [0x5a; 16])] {
+            write_plain_socket(&path, port, nonce);
+            let e = load_agent_target(&path, &startup, None)
+                .await
+                .err()
+                .unwrap();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+            // Agent is pinged to rewrite the file.
+            assert!(marker.exists());
+            fs::remove_file(&marker).unwrap();
+        }
+        write_plain_socket(&path, 4321, [0x5a; 16]);
+        let target = load_agent_target(&path, &startup, None).await.unwrap();
+        assert_eq!(target.port, 4321);
+        assert!(!marker.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_ping_with_home() {
+        let startup = AgentStartup::default();
+        let home = PathBuf::from(r"C:\Users\me\gnupg-work");
+        let cmd = gpg_command(&startup.program, Some(&home));
+        let envs: Vec<_> = cmd.as_std().get_envs().collect();
+        assert_eq!(envs, [(OsStr::new("GNUPGHOME"), Some(home.as_os_str()))]);
+        // The inherited home is left untouched.
+        let cmd = gpg_command(&startup.program, None);
+        assert_eq!(cmd.as_std().get_envs().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_socket_dir_recreated() {
+        let dir = temp_dir("socket-dir");
+        let (saved, socket_dir) = (dir.join("saved"), dir.join("run"));
+        write_plain_socket(&saved, 4321, [0x5a; 16]);
+        let path = socket_dir.join("S.gpg-agent.extra");
+        // Like `gpgconf --kill gpg-agent` removing the directory, which is created again with
+        // the socket file when agent starts.
+        let startup = AgentStartup {
+            program: PathBuf::from("cmd"),
+            args: vec![
+                "/C".to_owned(),
+                "mkdir".to_owned(),
+                socket_dir.display().to_string(),
+                "&&".to_owned(),
+                "copy".to_owned(),
+                saved.display().to_string(),
+                path.display().to_string(),
+            ],
+        };
+        let mut m = meta(Some(path.clone()));
+        let target = resolve_agent_target(&mut m, SocketType::Extra, &startup, None, false).await;
+        assert_eq!(target.unwrap().port, 4321);
+        assert_eq!(m.path, Some(path));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_oversized_socket_file() {
+        let dir = temp_dir("oversized-socket");
+        let path = dir.join("S.gpg-agent.extra");
+        let startup = AgentStartup::default();
+        let mut buffer = vec![];
+        fs::write(&path, vec![b'1'; MAX_SOCKET_FILE_LEN]).unwrap();
+        read_socket_file(&path, &mut buffer, &startup, None)
+            .await
+            .unwrap();
+        assert_eq!(buffer.len(), MAX_SOCKET_FILE_LEN);
+        fs::write(&path, vec![b'1'; MAX_SOCKET_FILE_LEN * 2]).unwrap();
+        let e = read_socket_file(&path, &mut buffer, &startup, None)
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        // Reading stops right after the limit.
+        assert_eq!(buffer.len(), MAX_SOCKET_FILE_LEN + 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_first_connections() {
+        let dir = temp_dir("concurrent-discovery");
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let path = dir.join("S.gpg-agent.extra");
+        write_plain_socket(&path, port, [0x5a; 16]);
+        let bridge = Bridge::new(BridgeOptions {
+            extra_socket: Some(path),
+            agent_startup: marker_startup(&dir.join("started")),
+            ..Default::default()
+        });
+        let agent = bridge.agent(None);
+        DISCOVERIES.with(|d| d.set(0));
+        let opens = (0..8).map(|_| open_agent(&agent, &bridge.core));
+        let accepts = async {
+            let mut conns = vec![];
+            for _ in 0..8 {
+                conns.push(listener.accept().await.unwrap());
+            }
+            conns
+        };
+        let both = futures::future::join(futures::future::join_all(opens), accepts);
+        let (opened, _accepted) = time::timeout(Duration::from_secs(10), both).await.unwrap();
+        assert!(opened.iter().all(Result::is_ok));
+        assert_eq!(DISCOVERIES.with(|d| d.get()), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_custom_agent_startup() {
+        let dir = temp_dir("agent-startup");
+        let marker = dir.join("started");
+        let bridge = Bridge::new(BridgeOptions {
+            extra_socket: Some(dir.join("missing").join("S.gpg-agent.extra")),
+            agent_startup: marker_startup(&marker),
+            ..Default::default()
+        });
+        let agent = bridge.agent(None);
+        // The startup command doesn't create the socket, so opening still fails.
+        assert!(open_agent(&agent, &bridge.core).await.is_err());
+        assert!(marker.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_agent_failure_cooldown() {
+        let dir = temp_dir("cooldown");
+        let cooldown = Duration::from_secs(10);
+        let bridge = Bridge::new(BridgeOptions {
+            extra_socket: Some(dir.join("S.gpg-agent.extra")),
+            agent_startup: marker_startup(&dir.join("started")),
+            agent_failure_cooldown: Some(cooldown),
+            ..Default::default()
+        });
+        let agent = bridge.agent(None);
+        let is_down = |e: io::Error| e.to_string().contains("agent is down");
+        let e = open_agent_with_retry(&agent, &bridge.core, 0)
+            .await
+            .unwrap_err();
+        assert!(!is_down(e));
+        time::advance(cooldown - Duration::from_secs(1)).await;
+        let e = open_agent_with_retry(&agent, &bridge.core, 0)
+            .await
+            .unwrap_err();
+        assert!(is_down(e));
+        time::advance(Duration::from_secs(1)).await;
+        let e = open_agent_with_retry(&agent, &bridge.core, 0)
+            .await
+            .unwrap_err();
+        assert!(!is_down(e));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_dirmngr_startup() {
+        let dir = temp_dir("dirmngr-startup");
+        let (agent_marker, dirmngr_marker) = (dir.join("agent"), dir.join("dirmngr"));
+        let bridge = Bridge::new(BridgeOptions {
+            dirmngr_socket: Some(dir.join("missing").join("S.dirmngr")),
+            agent_startup: marker_startup(&agent_marker),
+            dirmngr_startup: Some(marker_startup(&dirmngr_marker)),
+            ..Default::default()
+        });
+        let agent = bridge.core.dirmngr.clone();
+        assert!(open_agent(&agent, &bridge.core).await.is_err());
+        assert!(dirmngr_marker.exists());
+        assert!(!agent_marker.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_delegate_idle_timeout() {
+        let (client, mut client_peer) = tokio::io::duplex(64);
+        let (agent, mut agent_peer) = tokio::io::duplex(64);
+        let timeout = Duration::from_secs(10);
+        let start = time::Instant::now();
+        let forward = tokio::spawn(delegate(
+            0,
+            SocketType::Extra,
+            client,
+            agent,
+            None,
+            DelegateOptions {
+                buf_size: 16,
+                idle_timeout: Some(timeout),
+            },
+            std::future::pending(),
+        ));
+        let mut buf = [0; 4];
+        // Data in either direction defers the timeout.
+        time::sleep(Duration::from_secs(9)).await;
+        client_peer.write_all(b"ping").await.unwrap();
+        agent_peer.read_exact(&mut buf).await.unwrap();
+        time::sleep(Duration::from_secs(9)).await;
+        assert!(!forward.is_finished());
+        agent_peer.write_all(b"pong").await.unwrap();
+        client_peer.read_exact(&mut buf).await.unwrap();
+        let e = forward.await.unwrap().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(start.elapsed(), Duration::from_secs(28));
+    }
+
+    #[tokio::test]
+    async fn test_delegate_close_connections() {
+        let bridge = Bridge::new(BridgeOptions::default());
+        let (client, mut client_peer) = tokio::io::duplex(64);
+        let (agent, mut agent_peer) = tokio::io::duplex(64);
+        let stop = {
+            let core = bridge.core.clone();
+            async move { core.closed().await }
+        };
+        let forward = tokio::spawn(delegate(
+            0,
+            SocketType::Extra,
+            client,
+            agent,
+            None,
+            DelegateOptions {
+                buf_size: 16,
+                idle_timeout: None,
+            },
+            stop,
+        ));
+        client_peer.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        agent_peer.read_exact(&mut buf).await.unwrap();
+        bridge.close_connections();
+        time::timeout(Duration::from_secs(10), forward)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(client_peer.read(&mut buf).await.unwrap(), 0);
+        assert_eq!(agent_peer.read(&mut buf).await.unwrap(), 0);
+        // Connections accepted later are closed right away.
+        let (client, _client_peer) = tokio::io::duplex(64);
+        let stop = bridge.core.closed();
+        time::timeout(
+            Duration::from_secs(10),
+            delegate_ssh(1, client, None, 0, false, None, stop),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_delegate_ssh_idle_timeout() {
+        let (client, mut client_peer) = tokio::io::duplex(64);
+        let timeout = Some(Duration::from_secs(10));
+        let stop = std::future::pending();
+        let forward = tokio::spawn(delegate_ssh(0, client, None, 0, false, timeout, stop));
+        // The connection is closed without any reply, as no request is sent.
+        let mut resp = vec![];
+        client_peer.read_to_end(&mut resp).await.unwrap();
+        assert!(resp.is_empty());
+        let e = forward.await.unwrap().unwrap_err();
+        assert!(ssh::IdleTimeout::is(&e));
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn test_delegate_ssh_frame_too_large() {
+        let (client, mut client_peer) = tokio::io::duplex(64);
+        let forward = tokio::spawn(delegate_ssh(
+            0,
+            client,
+            None,
+            0,
+            false,
+            None,
+            std::future::pending(),
+        ));
+        let len = (ssh::PUTTY_IPC_MAXLEN as u32 - 3).to_be_bytes();
+        client_peer.write_all(&len).await.unwrap();
+        let mut resp = vec![];
+        client_peer.read_to_end(&mut resp).await.unwrap();
+        assert_eq!(resp, ssh::SSH_AGENT_FAILURE_FRAME);
+        let e = forward.await.unwrap().unwrap_err();
+        assert!(ssh::FrameTooLarge::is(&e));
+    }
+
+    /// Copies `from` to `to` with a buffer of fixed `size`, to compare with `copy`.
+    async fn copy_fixed<'a>(
+        from: &mut Pin<Box<dyn AsyncRead + Send + 'a>>,
+        to: &mut Pin<Box<dyn AsyncWrite + Send + 'a>>,
+        size: usize,
+    ) -> io::Result<()> {
+        let mut buf = ScrubBuf::new(size);
+        loop {
+            let cnt = from.read(&mut buf.0).await?;
+            if cnt == 0 {
+                return to.shutdown().await;
+            }
+            to.write_all(&buf.0[..cnt]).await?;
+        }
+    }
+
+    /// Forwards `total` bytes, written in `chunk`s, from one loopback TCP connection to
+    /// another. It's forwarded by `copy` unless `fixed` buffer size is specified.
+    async fn bench_forward(total: usize, chunk: usize, fixed: Option<usize>) -> Duration {
+        let sources = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let targets = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let source_addr = sources.local_addr().unwrap();
+        let target_addr = targets.local_addr().unwrap();
+        let writer = tokio::spawn(async move {
+            let mut s = TcpStream::connect(source_addr).await.unwrap();
+            let data = vec![0x5a; chunk];
+            for _ in 0..total / chunk {
+                s.write_all(&data).await.unwrap();
+            }
+        });
+        let drain = tokio::spawn(async move {
+            let mut s = TcpStream::connect(target_addr).await.unwrap();
+            let mut buf = vec![0; MAX_COPY_BUF_SIZE];
+            let mut read = 0;
+            loop {
+                match s.read(&mut buf).await.unwrap() {
+                    0 => return read,
+                    n => read += n,
+                }
+            }
+        });
+        let (mut source, _) = sources.accept().await.unwrap();
+        let (mut target, _) = targets.accept().await.unwrap();
+        let start = Instant::now();
+        let (mut from, _) = source.split_rw();
+        let (_, mut to) = target.split_rw();
+        match fixed {
+            Some(size) => copy_fixed(&mut from, &mut to, size).await.unwrap(),
+            None => {
+                let (transfer, eof, mut total) = (Transfer::new(), CloseReason::ClientEof, 0);
+                let buf_size = DEFAULT_COPY_BUF_SIZE;
+                let res = copy(
+                    &mut from,
+                    &mut to,
+                    |_| (),
+                    &mut total,
+                    eof,
+                    &transfer,
+                    buf_size,
+                );
+                res.await.unwrap();
+            }
+        }
+        writer.await.unwrap();
+        assert_eq!(drain.await.unwrap(), total / chunk * chunk);
+        start.elapsed()
+    }
+
+    /// Compares `copy` with fixed buffers of its smallest and largest sizes, for interactive
+    /// and bulk transfers. Run it by
+    /// `cargo test --release bench_copy_buffer -- --ignored --nocapture`.
+    #[tokio::test]
+    #[ignore]
+    async fn bench_copy_buffer() {
+        const TOTAL: usize = 256 * 1024 * 1024;
+        let modes = [
+            ("fixed 4KiB", Some(DEFAULT_COPY_BUF_SIZE)),
+            ("fixed 64KiB", Some(MAX_COPY_BUF_SIZE)),
+            ("adaptive", None),
+        ];
+        for chunk in [512, 65536] {
+            for (name, fixed) in modes {
+                let mut best = Duration::MAX;
+                for _ in 0..5 {
+                    best = best.min(bench_forward(TOTAL, chunk, fixed).await);
+                }
+                let mibps = TOTAL as f64 / best.as_secs_f64() / 1024.0 / 1024.0;
+                println!(
+                    "{:>6} bytes writes, {:<11}: {:>8.1?}, {:>5.0} MiB/s",
+                    chunk, name, best, mibps
+                );
+            }
+        }
+    }
+
+    /// A stream that fails every read and write.
+    struct Broken;
+
+    impl AsyncRead for Broken {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            _: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<io::Result<()>> {
+            std::task::Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()))
+        }
+    }
+
+    impl AsyncWrite for Broken {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            _: &[u8],
+        ) -> std::task::Poll<io::Result<usize>> {
+            std::task::Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()))
+        }
+
+        fn poll_flush(
+            self: Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<io::Result<()>> {
+            std::task::Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()))
+        }
+    }
+
+    /// Copies from `from` to `to` in the direction of `eof`, returns the error.
+    async fn copy_err(
+        from: impl AsyncRead + Send,
+        to: impl AsyncWrite + Send,
+        eof: CloseReason,
+    ) -> io::Error {
+        let mut from: Pin<Box<dyn AsyncRead + Send>> = Box::pin(from);
+        let mut to: Pin<Box<dyn AsyncWrite + Send>> = Box::pin(to);
+        let transfer = Transfer::new();
+        let res = copy(&mut from, &mut to, |_| (), &mut 0, eof, &transfer, 16).await;
+        res.unwrap_err()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_copy_scrub_on_cancel() {
+        let (mut client, client_peer) = tokio::io::duplex(64);
+        client.write_all(b"secret").await.unwrap();
+        let mut from: Pin<Box<dyn AsyncRead + Send>> = Box::pin(client_peer);
+        let mut to: Pin<Box<dyn AsyncWrite + Send>> = Box::pin(tokio::io::sink());
+        let transfer = Transfer::new();
+        let (mut total, eof) = (0, CloseReason::ClientEof);
+        util::SCRUBBED.with(|s| s.borrow_mut().clear());
+        // Nothing follows the secret, so copy is cancelled while waiting for the next read.
+        let copy = copy(&mut from, &mut to, |_| (), &mut total, eof, &transfer, 16);
+        assert!(time::timeout(Duration::from_secs(1), copy).await.is_err());
+        assert_eq!(total, 6);
+        let scrubbed = util::SCRUBBED.with(|s| s.borrow().clone());
+        assert_eq!(scrubbed, [(true, true)]);
+    }
+
+    #[tokio::test]
+    async fn test_copy_error_side() {
+        // Client to agent.
+        let e = copy_err(Broken, tokio::io::sink(), CloseReason::ClientEof).await;
+        assert!(!AgentSideError::is(&e));
+        let e = copy_err(&b"data"[..], Broken, CloseReason::ClientEof).await;
+        assert!(AgentSideError::is(&e));
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionReset);
+        // Agent to client.
+        let e = copy_err(Broken, tokio::io::sink(), CloseReason::AgentEof).await;
+        assert!(AgentSideError::is(&e));
+        let e = copy_err(&b"data"[..], Broken, CloseReason::AgentEof).await;
+        assert!(!AgentSideError::is(&e));
     }
 }
@@ -0,0 +1,123 @@
+// Writes logs to a file, which is rolled over when it grows too large.
+//
+// Rolled files are named by appending `.1`, `.2`, ... to the path, `.1` being the newest.
+
+use env_logger::filter::{self, Filter};
+use log::{Log, Metadata, Record};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    /// `None` means never rotating.
+    max_size: Option<u64>,
+    /// Number of rolled files to keep.
+    keep: usize,
+}
+
+fn rolled_path(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_size: Option<u64>, keep: usize) -> io::Result<RotatingFile> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            file,
+            size,
+            max_size,
+            keep,
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.keep == 0 {
+            self.file.set_len(0)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                let from = rolled_path(&self.path, n);
+                if from.exists() {
+                    fs::rename(from, rolled_path(&self.path, n + 1))?;
+                }
+            }
+            fs::rename(&self.path, rolled_path(&self.path, 1))?;
+            self.file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self
+            .max_size
+            .is_some_and(|m| self.size > 0 && self.size + line.len() as u64 > m)
+        {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+/// A logger that writes to a file instead of stderr, filtered by `RUST_LOG` as well.
+pub struct FileLogger {
+    filter: Filter,
+    file: parking_lot::Mutex<RotatingFile>,
+}
+
+impl FileLogger {
+    /// Installs the logger writing to `path`.
+    ///
+    /// If `max_size` is specified, the file is rolled over before it exceeds the size, and
+    /// at most `keep` rolled files are kept.
+    pub fn init(path: PathBuf, max_size: Option<u64>, keep: usize) -> io::Result<()> {
+        let filter = filter::Builder::from_env("RUST_LOG").build();
+        let file = RotatingFile::open(path, max_size, keep)?;
+        let level = filter.filter();
+        let logger = Box::new(FileLogger {
+            filter,
+            file: parking_lot::Mutex::new(file),
+        });
+        log::set_logger(Box::leak(logger))
+            .map_err(|e| io::Error::new(io::ErrorKind::AlreadyExists, e.to_string()))?;
+        log::set_max_level(level);
+        Ok(())
+    }
+}
+
+impl Log for FileLogger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if !self.filter.matches(record) {
+            return;
+        }
+        let line = format!(
+            "{} {:<5} {} > {}\n",
+            humantime::format_rfc3339_seconds(SystemTime::now()),
+            record.level(),
+            record.target(),
+            record.args()
+        );
+        // Errors can't be logged, otherwise it may recurse.
+        let _ = self.file.lock().write_line(&line);
+    }
+
+    fn flush(&self) {
+        let _ = self.file.lock().file.flush();
+    }
+}
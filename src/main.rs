@@ -1,64 +1,875 @@
-use clap::Parser;
+use clap::{Parser, ValueEnum};
+use env_logger::WriteStyle;
+use futures::future;
 use gpg_bridge::other_error;
-use gpg_bridge::SocketType;
+use gpg_bridge::{
+    AgentStartup, Auditor, BindOptions, Bridge, BridgeOptions, FileLogger, ListenerInfo,
+    SocketType, DEFAULT_COPY_BUF_SIZE, DEFAULT_MAX_MESSAGE_LOG_BYTES, DEFAULT_PIPE_QUEUE_DEPTH,
+    DEFAULT_SSH_READ_BUFFER, DEFAULT_WARMUP_RETRIES,
+};
+use log::{info, warn};
+use std::net::IpAddr;
 use std::os::windows::process::CommandExt;
+use std::path::{Path, PathBuf};
 use std::process::Command;
-use std::{env, io};
+use std::time::Duration;
+use std::{env, fs, io};
+use tokio::signal::windows as signal;
+use tokio::{runtime, time};
+use windows::core::w;
+use windows::Win32::Foundation::{CloseHandle, ERROR_ALREADY_EXISTS, HANDLE};
+use windows::Win32::System::Threading::{
+    CreateMutexW, GetCurrentProcess, SetPriorityClass, BELOW_NORMAL_PRIORITY_CLASS,
+    CREATE_NEW_CONSOLE, CREATE_NEW_PROCESS_GROUP, CREATE_NO_WINDOW, DETACHED_PROCESS,
+    NORMAL_PRIORITY_CLASS,
+};
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Color {
+    /// Colors only when stderr is a terminal
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Priority {
+    /// Yields to interactive applications
+    BelowNormal,
+    Normal,
+}
+
+/// How long connections are given to finish closing when exiting.
+const CLOSE_GRACE: Duration = Duration::from_millis(100);
 
 #[derive(Parser)]
 #[command(name = "gpg-bridge")]
 #[command(version, about)]
 struct GpgBridge {
-    /// Sets the listenning address to bridge the ssh socket
-    #[arg(long, value_name("ADDRESS"), required_unless_present("extra"))]
+    /// Sets the listenning address to bridge the ssh socket, paths ending with .sock are bound as
+    /// Unix domain sockets
+    #[arg(
+        long,
+        value_name("ADDRESS"),
+        required_unless_present_any([
+            "extra",
+            "extra_from_vsock",
+            "dirmngr",
+            "extra_probe_only",
+            "control",
+            "check_keys",
+            "loopback_test",
+            "debug_parse_socket"
+        ])
+    )]
     ssh: Option<String>,
-    /// Sets the listenning to bridge the extra socket
-    #[arg(long, value_name("ADDRESS"), required_unless_present("ssh"))]
-    extra: Option<String>,
-    /// Sets the path to gnupg extra socket optionaly
+    /// Sets the listenning to bridge the extra socket, can be repeated
+    #[arg(
+        long,
+        value_name("ADDRESS"),
+        required_unless_present_any([
+            "ssh",
+            "extra_from_vsock",
+            "dirmngr",
+            "extra_probe_only",
+            "control",
+            "check_keys",
+            "loopback_test",
+            "debug_parse_socket"
+        ])
+    )]
+    extra: Vec<String>,
+    /// Sets the Hyper-V socket port to bridge the extra socket for VMs and containers, same as
+    /// --extra vsock:<PORT>, can be repeated. Every VM and container on the host can connect
+    /// to it
+    #[arg(long, value_name("PORT"))]
+    extra_from_vsock: Vec<u32>,
+    /// Sets the listenning address that only forwards GETINFO, NOP and BYE to the extra socket,
+    /// for health checks
+    #[arg(long, value_name("ADDRESS"))]
+    extra_probe_only: Option<String>,
+    /// Sets the listenning address to bridge the dirmngr socket
+    #[arg(long, value_name("ADDRESS"))]
+    dirmngr: Option<String>,
+    /// Sets GNUPGHOME of the agent for each --extra in the same order, followed by
+    /// --extra-from-vsock
+    #[arg(long, value_name("DIR"), value_parser = parse_path)]
+    gnupghome: Vec<PathBuf>,
+    /// Wraps data of --extra TCP clients in checksummed frames, the peer has to frame as well
+    #[arg(long)]
+    framed: bool,
+    /// Requires framed clients to send a correlation id of at most 64 printable characters as
+    /// the first frame, which is logged with the connection
+    #[arg(long, requires("framed"))]
+    framed_correlation: bool,
+    /// Fails a ssh request if agent doesn't reply in the given seconds
+    #[arg(long, value_name("SECS"))]
+    ssh_request_timeout: Option<u64>,
+    /// Closes a connection if no data is transferred for the given seconds, or a ssh client
+    /// sends no request, disabled by default
+    #[arg(long, value_name("SECS"))]
+    idle_timeout: Option<u64>,
+    /// Fails new connections immediately for the given seconds after the agent can't be
+    /// opened, instead of discovering and starting it for every one
+    #[arg(long, value_name("SECS"))]
+    agent_failure_cooldown: Option<u64>,
+    /// Sets the initial size of buffer forwarding extra and dirmngr sockets, it grows for large
+    /// transfers
+    #[arg(long, value_name("BYTES"), default_value_t = DEFAULT_COPY_BUF_SIZE)]
+    buffer_size: usize,
+    /// Sets the size of buffer reading ssh requests, 0 to read them directly
+    #[arg(long, value_name("BYTES"), default_value_t = DEFAULT_SSH_READ_BUFFER)]
+    ssh_read_buffer: usize,
+    /// Logs time spent in every phase of forwarding a request at debug level
+    #[arg(long)]
+    trace_timing: bool,
+    /// Sets how many bytes of every message are logged at trace level
+    #[arg(long, value_name("N"), default_value_t = DEFAULT_MAX_MESSAGE_LOG_BYTES)]
+    max_message_log_bytes: usize,
+    /// Logs the fingerprint of the key used by every ssh sign request
+    #[arg(long)]
+    log_ssh_signs: bool,
+    /// Names the shared memory of every ssh request by a random GUID, so it never collides with
+    /// other processes
+    #[arg(long)]
+    ssh_map_name_guid: bool,
+    /// Sets the magic in hex sent to Pageant compatible agent, defaults to 804e50ba
+    #[arg(long, value_name("HEX"), value_parser = parse_hex)]
+    pageant_magic: Option<usize>,
+    /// Sets the gpg-connect-agent used to start gpg-agent and dirmngr
+    #[arg(long, value_name("EXE"))]
+    gpg_connect_agent_path: Option<PathBuf>,
+    /// Sets the whole command line used to start gpg-agent instead of `gpg-connect-agent /bye`
+    #[arg(long, value_name("CMD"), conflicts_with("gpg_connect_agent_path"))]
+    agent_startup_cmd: Option<String>,
+    /// Runs the command to learn port and nonce of the agent instead of reading extra socket,
+    /// it should print `port <PORT>` and `nonce <HEX>` lines
+    #[arg(long, value_name("CMD"), conflicts_with("extra_socket"))]
+    discover_via_command: Option<String>,
+    /// Sets the path to gnupg extra socket optionaly, repeat it to fail over to other agents in
+    /// order
+    #[arg(long, value_name("PATH"), value_parser = parse_path)]
+    extra_socket: Vec<PathBuf>,
+    /// Sets the path to dirmngr socket optionaly
+    #[arg(long, value_name("PATH"), value_parser = parse_path)]
+    dirmngr_socket: Option<PathBuf>,
+    /// Appends high level events of requests to extra socket to the file for auditing
     #[arg(long, value_name("PATH"))]
-    extra_socket: Option<String>,
-    /// Runs the program as a background daemon
+    audit_extra: Option<PathBuf>,
+    /// Allows joining a named pipe that has been created by another trusted process
+    #[arg(long)]
+    no_first_pipe_instance: bool,
+    /// Sets the number of connected pipe clients that can wait to be handled
+    #[arg(long, value_name("N"), default_value_t = DEFAULT_PIPE_QUEUE_DEPTH)]
+    pipe_queue_depth: usize,
+    /// Allows clients from other machines to connect to named pipes
+    #[arg(long)]
+    pipe_allow_remote: bool,
+    /// Rejects pipe clients not running as the same user as the bridge
+    #[arg(long)]
+    require_same_user: bool,
+    /// Binds TCP listeners with SO_EXCLUSIVEADDRUSE instead of SO_REUSEADDR, so no other socket
+    /// can bind the same port, but restarting may fail while old connections linger
+    #[arg(long)]
+    tcp_exclusive: bool,
+    /// Creates named pipes under \\.\pipe\Local\ that only processes in the current logon
+    /// session can connect to
+    #[arg(long)]
+    pipe_local_namespace: bool,
+    /// Sets the local IPv4 address to connect to gpg agent from
+    #[arg(long, value_name("ADDR"))]
+    agent_bind_addr: Option<IpAddr>,
+    /// Sets how many times to retry if the first connection to gpg agent fails
+    #[arg(long, value_name("N"), default_value_t = DEFAULT_WARMUP_RETRIES)]
+    warmup_retries: usize,
+    /// Forwards to the standard socket if the extra socket can't be loaded
+    #[arg(long)]
+    extra_fallback_standard: bool,
+    /// Allows the control command `upgrade` to hand off listeners to a new process
+    #[arg(long, requires("control"))]
+    reexec_on_upgrade: bool,
+    /// Refuses to start if another instance is running in the same session, no matter which
+    /// addresses it listens on
+    #[arg(long)]
+    single_instance: bool,
+    /// Runs the program as a background daemon, otherwise it stays in current console and logs
+    /// to stderr
     #[arg(long)]
     detach: bool,
+    /// Opens a new console window for the daemon started by --detach instead of hiding it
+    #[arg(long, requires("detach"))]
+    show_console: bool,
+    /// Gives the daemon started by --detach a console without window instead of none
+    #[arg(long, requires("detach"), conflicts_with("show_console"))]
+    detach_keep_console: bool,
+    /// Sets when to colorize logs
+    #[arg(long, value_name("WHEN"), value_enum, default_value_t = Color::Auto)]
+    color: Color,
+    /// Writes logs to the file instead of stderr
+    #[arg(long, value_name("PATH"))]
+    log_file: Option<PathBuf>,
+    /// Rolls the log file over before it exceeds the given bytes
+    #[arg(long, value_name("BYTES"), requires("log_file"))]
+    log_max_size: Option<u64>,
+    /// Sets how many rolled log files are kept
+    #[arg(long, value_name("N"), default_value_t = 5, requires("log_max_size"))]
+    log_keep: usize,
+    /// Sets the priority class of the process
+    #[arg(long, value_name("PRIORITY"), value_enum, default_value_t = Priority::Normal)]
+    priority: Priority,
+    /// Sets the number of runtime worker threads, 0 means running on current thread
+    #[arg(long, value_name("N"), default_value_t = 0)]
+    runtime_threads: usize,
+    /// Sets the named pipe to accept commands that add or remove listeners, only current
+    /// user can connect to it
+    #[arg(long, value_name("PIPE"))]
+    control: Option<String>,
+    /// Writes the bound addresses to the file once all listeners are ready, and removes it
+    /// on exit
+    #[arg(long, value_name("PATH"), value_parser = parse_path)]
+    ready_file: Option<PathBuf>,
+    /// Logs connection and traffic counters every given seconds
+    #[arg(long, value_name("SECS"))]
+    summary_interval: Option<u64>,
+    /// Exits after running for the given seconds regardless of activity
+    #[arg(long, value_name("SECS"))]
+    max_runtime: Option<u64>,
+    /// Exits after the given number of connections are accepted and finished
+    #[arg(long, value_name("N"))]
+    max_connections: Option<u64>,
+    /// Restarts gpg-agent after forwarding to it fails the given times in a row, which also
+    /// affects its other clients
+    #[arg(long, value_name("N"))]
+    reconnect_agent_on_error_count: Option<usize>,
+    /// Prints the keys offered by the agent via ssh and extra socket, then exits
+    #[arg(long)]
+    check_keys: bool,
+    /// Round trips data through a temporary named pipe to an in-process echo instead of the
+    /// agent, then exits
+    #[arg(long)]
+    loopback_test: bool,
+    /// Prints the status of the bridge listening on --control as JSON, then exits
+    #[arg(long, requires("control"))]
+    status_json: bool,
+    /// Prints port and nonce parsed from the socket file, discovers extra socket if no PATH
+    #[arg(
+        long,
+        value_name("PATH"),
+        num_args(0..=1),
+        default_missing_value(""),
+        hide(true)
+    )]
+    debug_parse_socket: Option<PathBuf>,
+}
+
+fn init_logger(color: Color) {
+    let mut builder = pretty_env_logger::formatted_builder();
+    if let Ok(filters) = env::var("RUST_LOG") {
+        builder.parse_filters(&filters);
+    }
+    builder.write_style(match color {
+        Color::Auto => WriteStyle::Auto,
+        Color::Always => WriteStyle::Always,
+        Color::Never => WriteStyle::Never,
+    });
+    builder.init();
 }
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> io::Result<()> {
-    pretty_env_logger::init();
+/// Holds the mutex that marks a running instance, see `--single-instance`.
+struct InstanceGuard(HANDLE);
+
+impl Drop for InstanceGuard {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = CloseHandle(self.0);
+        }
+    }
+}
+
+/// Creates the mutex named after the program in the session, fails if it exists already.
+///
+/// A `successor` started by `upgrade` joins the mutex of its predecessor instead, so it stays
+/// held after the predecessor exits.
+fn acquire_single_instance(successor: bool) -> io::Result<InstanceGuard> {
+    let handle = unsafe { CreateMutexW(None, false, w!("Local\\gpg-bridge")) }
+        .map_err(|e| other_error(format!("failed to create instance mutex: {:?}", e)))?;
+    let exists = io::Error::last_os_error().raw_os_error() == Some(ERROR_ALREADY_EXISTS.0 as i32);
+    let guard = InstanceGuard(handle);
+    if exists && !successor {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "another gpg-bridge is running in this session, stop it first or drop --single-instance",
+        ));
+    }
+    Ok(guard)
+}
+
+fn set_priority(priority: Priority) -> io::Result<()> {
+    let class = match priority {
+        Priority::BelowNormal => BELOW_NORMAL_PRIORITY_CLASS,
+        Priority::Normal => NORMAL_PRIORITY_CLASS,
+    };
+    unsafe { SetPriorityClass(GetCurrentProcess(), class) }
+        .map_err(|e| other_error(format!("failed to set priority: {:?}", e)))
+}
+
+fn main() -> io::Result<()> {
+    // Before any thread is started, see `load_inherited_listeners`.
+    let successor = gpg_bridge::load_inherited_listeners();
     let cfg = GpgBridge::parse();
-    if cfg.detach {
-        let _ = gpg_bridge::ping_gpg_agent().await;
+    match &cfg.log_file {
+        Some(path) => FileLogger::init(path.clone(), cfg.log_max_size, cfg.log_keep)?,
+        None => init_logger(cfg.color),
+    }
+    set_priority(cfg.priority)?;
+    let mut builder = match cfg.runtime_threads {
+        0 => runtime::Builder::new_current_thread(),
+        n => {
+            let mut builder = runtime::Builder::new_multi_thread();
+            builder.worker_threads(n);
+            builder
+        }
+    };
+    let runtime = builder.enable_all().build()?;
+    runtime.block_on(run(cfg, successor))
+}
+
+async fn debug_parse_socket(path: PathBuf, startup: &AgentStartup) -> io::Result<()> {
+    let path = Some(path).filter(|p| !p.as_os_str().is_empty());
+    let info = gpg_bridge::parse_socket(path, startup).await?;
+    for (i, path) in info.paths.iter().enumerate() {
+        if i == 0 {
+            println!("path: {}", path.display());
+        } else {
+            println!("redirected to: {}", path.display());
+        }
+    }
+    println!("format: {:?}", info.format);
+    println!("port: {}", info.target.port);
+    if let Some(handshake) = &info.target.handshake {
+        let nonce: String = handshake.iter().map(|b| format!("{:02x}", b)).collect();
+        println!("nonce: {}", nonce);
+    }
+    Ok(())
+}
 
-        let mut args = env::args();
-        let mut cmd = Command::new(args.next().unwrap());
-        for arg in args {
-            if arg != "--detach" {
-                cmd.arg(arg);
+async fn check_keys(bridge: &Bridge, home: Option<PathBuf>) -> io::Result<()> {
+    let mut res = Ok(());
+    match bridge.ssh_identities().await {
+        Ok(identities) => {
+            println!("ssh: {} keys", identities.len());
+            for id in identities {
+                println!("  {} {} {}", id.key_type, id.fingerprint, id.comment);
             }
         }
-        return cmd
-            .creation_flags(0x0000_0200 | 0x0000_0008 | 0x0400_0000)
-            .spawn()
-            .map(|_| ());
+        Err(e) => {
+            println!("ssh: {}", e);
+            res = Err(e);
+        }
+    }
+    match bridge.extra_keygrips(home).await {
+        Ok(keygrips) => {
+            println!("extra: {} keys", keygrips.len());
+            for keygrip in keygrips {
+                println!("  {}", keygrip);
+            }
+        }
+        Err(e) => {
+            println!("extra: {}", e);
+            res = Err(e);
+        }
+    }
+    res
+}
+
+fn agent_startup(cfg: &GpgBridge) -> io::Result<AgentStartup> {
+    if let Some(program) = &cfg.gpg_connect_agent_path {
+        return Ok(AgentStartup {
+            program: program.clone(),
+            ..Default::default()
+        });
+    }
+    match &cfg.agent_startup_cmd {
+        Some(cmd) => parse_command(cmd, "--agent-startup-cmd"),
+        None => Ok(AgentStartup::default()),
+    }
+}
+
+/// Splits `cmd` by whitespaces into program and arguments.
+///
+/// Whitespaces between double quotes don't split, so paths like
+/// `"C:\Program Files (x86)\GnuPG\bin\gpg-connect-agent.exe"` can be used. Quotes are
+/// removed, backslashes are kept as is.
+fn parse_command(cmd: &str, flag: &str) -> io::Result<AgentStartup> {
+    let invalid =
+        |msg: &str| io::Error::new(io::ErrorKind::InvalidInput, format!("{} {}", flag, msg));
+    let mut words = vec![];
+    let mut word: Option<String> = None;
+    let mut quoted = false;
+    for c in cmd.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                word.get_or_insert_with(String::new);
+            }
+            c if c.is_whitespace() && !quoted => words.extend(word.take()),
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quoted {
+        return Err(invalid("has an unterminated quote"));
+    }
+    words.extend(word);
+    let mut words = words.into_iter();
+    match words.next() {
+        Some(program) => Ok(AgentStartup {
+            program: PathBuf::from(program),
+            args: words.collect(),
+        }),
+        None => Err(invalid("should not be empty")),
+    }
+}
+
+async fn log_summary(bridge: Bridge, interval: Duration) {
+    let mut ticker = time::interval(interval);
+    // The first tick completes immediately.
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        let stats = bridge.stats();
+        info!(
+            "summary: {} connections, {} active, received {}, replied {}",
+            stats.connections, stats.active, stats.received, stats.replied
+        );
+    }
+}
+
+/// Expands environment variables like `%APPDATA%` in paths.
+fn parse_path(s: &str) -> Result<PathBuf, String> {
+    gpg_bridge::expand_env_vars(s)
+        .map(PathBuf::from)
+        .map_err(|e| e.to_string())
+}
+
+fn parse_hex(s: &str) -> Result<usize, String> {
+    let digits = s.strip_prefix("0x").unwrap_or(s);
+    usize::from_str_radix(digits, 16).map_err(|e| format!("invalid hex {}: {}", s, e))
+}
+
+/// Tells which listener fails, so the cause is clear when several sockets are bridged.
+fn listener_failed(name: &str, addr: &str, e: io::Error) -> io::Error {
+    io::Error::new(
+        e.kind(),
+        format!("{} bridge on {} failed: {}", name, addr, e),
+    )
+}
+
+/// Writes `listeners` to `path` one per line as `<ssh|extra|dirmngr> <ADDRESS> [GNUPGHOME]`.
+///
+/// The content is written to a temporary file first, so watchers never see a partial file.
+fn write_ready_file(path: &Path, listeners: &[ListenerInfo]) -> io::Result<()> {
+    let mut content = String::new();
+    for l in listeners {
+        content.push_str(l.ty.short_name());
+        content.push(' ');
+        content.push_str(&l.addr);
+        if let Some(home) = &l.gnupghome {
+            content.push(' ');
+            content.push_str(&home.to_string_lossy());
+        }
+        content.push('\n');
+    }
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, content)?;
+    fs::rename(&tmp, path)
+}
+
+/// Flags that only make sense to the process starting the daemon.
+const DETACH_FLAGS: &[&str] = &["--detach", "--show-console", "--detach-keep-console"];
+
+/// Gets the arguments of the daemon from `argv` of current process, the program is not
+/// included.
+///
+/// Detach flags are removed, or the daemon would detach again. They are matched by name
+/// so forms like `--detach=true` are removed as well.
+fn build_detached_args(argv: &[String]) -> Vec<String> {
+    argv.iter()
+        .skip(1)
+        .filter(|arg| {
+            let name = arg.split('=').next().unwrap();
+            !DETACH_FLAGS.contains(&name)
+        })
+        .cloned()
+        .collect()
+}
+
+async fn run(mut cfg: GpgBridge, successor: bool) -> io::Result<()> {
+    let vsock_extras = cfg
+        .extra_from_vsock
+        .iter()
+        .map(|port| format!("vsock:{}", port));
+    cfg.extra.extend(vsock_extras);
+    let startup = agent_startup(&cfg)?;
+    if let Some(cmd) = &cfg.discover_via_command {
+        gpg_bridge::set_discovery_command(parse_command(cmd, "--discover-via-command")?)?;
+    }
+    gpg_bridge::set_trace_timing(cfg.trace_timing);
+    gpg_bridge::set_max_message_log_bytes(cfg.max_message_log_bytes);
+    gpg_bridge::set_guid_map_names(cfg.ssh_map_name_guid);
+    if let Some(magic) = cfg.pageant_magic {
+        gpg_bridge::set_pageant_magic(magic)?;
+    }
+    // Listeners of --extra with --gnupghome only need the agents of their homes, which are
+    // started later.
+    let uses_default_agent = cfg.ssh.is_some()
+        || cfg.dirmngr.is_some()
+        || cfg.extra_probe_only.is_some()
+        || cfg.control.is_some()
+        || cfg.check_keys
+        || cfg.gnupghome.is_empty();
+    if cfg.detach {
+        if uses_default_agent {
+            let _ = gpg_bridge::ping_gpg_agent_with(&startup, None).await;
+        }
+
+        let argv: Vec<_> = env::args().collect();
+        let mut cmd = Command::new(&argv[0]);
+        cmd.args(build_detached_args(&argv));
+        // A new process group, so Ctrl-C in current console doesn't reach the daemon.
+        let mut flags = CREATE_NEW_PROCESS_GROUP;
+        if cfg.show_console {
+            // A visible console of its own.
+            flags |= CREATE_NEW_CONSOLE;
+        } else if cfg.detach_keep_console {
+            // A console of its own without window, console output still succeeds but is
+            // only visible when redirected.
+            flags |= CREATE_NO_WINDOW;
+        } else {
+            // No console at all, so it outlives current console.
+            flags |= DETACHED_PROCESS;
+        }
+        return cmd.creation_flags(flags.0).spawn().map(|_| ());
     }
 
-    let ssh_from = cfg.ssh;
+    if let Some(path) = cfg.debug_parse_socket {
+        return debug_parse_socket(path, &startup).await;
+    }
+    if cfg.status_json {
+        let addr = cfg.control.as_deref().unwrap();
+        print!("{}", gpg_bridge::query_status(addr).await?);
+        return Ok(());
+    }
+    if cfg.loopback_test {
+        let len = gpg_bridge::loopback_test().await?;
+        println!("loopback: {} bytes round tripped", len);
+        return Ok(());
+    }
+    // Released when returning.
+    let _instance = if cfg.single_instance {
+        Some(acquire_single_instance(successor)?)
+    } else {
+        None
+    };
+
+    // Attempt to setup gpg-agent if it's not up yet.
+    if uses_default_agent {
+        let _ = gpg_bridge::ping_gpg_agent_with(&startup, None).await;
+    }
+    let audit_extra = match cfg.audit_extra {
+        Some(path) => Some(Auditor::open(&path)?),
+        None => None,
+    };
+    let mut extra_sockets = cfg.extra_socket.into_iter();
+    let bridge = Bridge::new(BridgeOptions {
+        extra_socket: extra_sockets.next(),
+        extra_socket_fallbacks: extra_sockets.collect(),
+        dirmngr_socket: cfg.dirmngr_socket,
+        audit_extra,
+        bind: BindOptions {
+            first_pipe_instance: !cfg.no_first_pipe_instance,
+            pipe_queue_depth: cfg.pipe_queue_depth,
+            pipe_reject_remote: !cfg.pipe_allow_remote,
+            pipe_require_same_user: cfg.require_same_user,
+            pipe_owner_only: false,
+            pipe_session_only: false,
+            tcp_exclusive: cfg.tcp_exclusive,
+        },
+        pipe_local_namespace: cfg.pipe_local_namespace,
+        agent_bind_addr: cfg.agent_bind_addr,
+        warmup_retries: Some(cfg.warmup_retries),
+        agent_startup: startup.clone(),
+        dirmngr_startup: cfg.gpg_connect_agent_path.map(AgentStartup::dirmngr),
+        extra_fallback_standard: cfg.extra_fallback_standard,
+        reexec_on_upgrade: cfg.reexec_on_upgrade,
+        framed: cfg.framed,
+        framed_correlation: cfg.framed_correlation,
+        ssh_request_timeout: cfg.ssh_request_timeout.map(Duration::from_secs),
+        agent_failure_cooldown: cfg.agent_failure_cooldown.map(Duration::from_secs),
+        idle_timeout: cfg.idle_timeout.map(Duration::from_secs),
+        ssh_read_buffer: Some(cfg.ssh_read_buffer),
+        ssh_log_signs: cfg.log_ssh_signs,
+        accept_filter: None,
+        max_connections: cfg.max_connections,
+        reconnect_agent_on_error_count: cfg.reconnect_agent_on_error_count,
+        copy_buffer_size: Some(cfg.buffer_size),
+    });
+
+    if cfg.check_keys {
+        return check_keys(&bridge, cfg.gnupghome.into_iter().next()).await;
+    }
+    if let Err(e) = bridge.watch_resume() {
+        warn!(
+            "failed to watch system resume, agents may be stale after sleep: {}",
+            e
+        );
+    }
+
+    // Listeners to be bound before the bridge is ready, control listener is not included.
+    let expected = cfg.extra.len()
+        + [&cfg.ssh, &cfg.extra_probe_only, &cfg.dirmngr]
+            .iter()
+            .filter(|a| a.is_some())
+            .count();
+    let (ssh_from, ssh_bridge) = (cfg.ssh, bridge.clone());
     let ssh_task = async move {
         if let Some(from_addr) = ssh_from {
-            return gpg_bridge::bridge(SocketType::Ssh, from_addr, None).await;
+            let res = ssh_bridge.serve(SocketType::Ssh, from_addr.clone(), None);
+            return res.await.map_err(|e| listener_failed("ssh", &from_addr, e));
         }
         Ok(())
     };
-    let (extra_from, extra_to) = (cfg.extra, cfg.extra_socket);
-    let extra_task = async move {
-        if let Some(from_addr) = extra_from {
-            return gpg_bridge::bridge(SocketType::Extra, from_addr, extra_to).await;
+    if !cfg.gnupghome.is_empty() && cfg.gnupghome.len() != cfg.extra.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--gnupghome should be specified for every --extra",
+        ));
+    }
+    // Agent always listens on 127.0.0.1.
+    if cfg.agent_bind_addr.is_some_and(|a| !a.is_ipv4()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--agent-bind-addr should be an IPv4 address",
+        ));
+    }
+    let mut homes = cfg.gnupghome.into_iter().map(Some).collect::<Vec<_>>();
+    homes.resize(cfg.extra.len(), None);
+    for home in homes.iter().flatten() {
+        let _ = gpg_bridge::ping_gpg_agent_with(&startup, Some(home)).await;
+    }
+    let extra_tasks = cfg.extra.into_iter().zip(homes).map(|(from_addr, home)| {
+        let extra_bridge = bridge.clone();
+        async move {
+            let res = extra_bridge.serve(SocketType::Extra, from_addr.clone(), home);
+            res.await
+                .map_err(|e| listener_failed("extra", &from_addr, e))
+        }
+    });
+    let extra_task = future::try_join_all(extra_tasks);
+    let (probe_from, probe_bridge) = (cfg.extra_probe_only, bridge.clone());
+    let probe_task = async move {
+        if let Some(from_addr) = probe_from {
+            let res = probe_bridge.serve_probe(from_addr.clone());
+            return res
+                .await
+                .map_err(|e| listener_failed("extra probe", &from_addr, e));
         }
         Ok(())
     };
-    match tokio::try_join!(ssh_task, extra_task) {
-        Ok(_) => Ok(()),
-        Err(e) => return Err(other_error(format!("failed to join tasks {:?}", e))),
+    let (dirmngr_from, dirmngr_bridge) = (cfg.dirmngr, bridge.clone());
+    let dirmngr_task = async move {
+        if let Some(from_addr) = dirmngr_from {
+            let res = dirmngr_bridge.serve(SocketType::Dirmngr, from_addr.clone(), None);
+            return res
+                .await
+                .map_err(|e| listener_failed("dirmngr", &from_addr, e));
+        }
+        Ok(())
+    };
+    let (control_from, control_bridge) = (cfg.control, bridge.clone());
+    let control_task = async move {
+        if let Some(addr) = control_from {
+            let res = gpg_bridge::serve_control(control_bridge, addr.clone());
+            return res.await.map_err(|e| listener_failed("control", &addr, e));
+        }
+        Ok(())
+    };
+    let (ready_file, ready_bridge) = (cfg.ready_file.clone(), bridge.clone());
+    let ready_task = async move {
+        if let Some(path) = ready_file {
+            let listeners = ready_bridge.bound_listeners(expected).await;
+            write_ready_file(&path, &listeners)
+                .map_err(|e| other_error(format!("failed to write {}: {}", path.display(), e)))?;
+            info!("ready, listeners are written to {}", path.display());
+        }
+        Ok(())
+    };
+    if let Some(secs) = cfg.summary_interval.filter(|s| *s > 0) {
+        tokio::spawn(log_summary(bridge.clone(), Duration::from_secs(secs)));
+    }
+    let max_runtime = cfg.max_runtime;
+    let max_runtime = async move {
+        match max_runtime {
+            Some(secs) => time::sleep(Duration::from_secs(secs)).await,
+            None => future::pending::<()>().await,
+        }
+    };
+    // Ctrl-C waits for accepted connections to finish, while Ctrl-Break exits immediately.
+    let mut ctrl_c = signal::ctrl_c()?;
+    let mut ctrl_break = signal::ctrl_break()?;
+    // Windows terminates the process shortly after the console is closed, there is no time
+    // to drain.
+    let mut ctrl_close = signal::ctrl_close()?;
+    let mut draining = false;
+    let res = tokio::select! {
+        res = async { tokio::try_join!(ssh_task, extra_task, probe_task, dirmngr_task, control_task, ready_task) } => {
+            res.map(|_| ())
+        }
+        _ = max_runtime => {
+            info!("max runtime reached");
+            bridge.shutdown();
+            Ok(())
+        }
+        _ = bridge.exhausted() => {
+            info!("draining accepted connections");
+            draining = true;
+            Ok(())
+        }
+        _ = bridge.upgraded() => {
+            info!("upgraded, draining accepted connections");
+            draining = true;
+            Ok(())
+        }
+        _ = ctrl_c.recv() => {
+            info!("draining accepted connections, press Ctrl-C again or Ctrl-Break to exit now");
+            bridge.shutdown();
+            draining = true;
+            Ok(())
+        }
+        _ = ctrl_break.recv() => {
+            info!("exiting now");
+            Ok(())
+        }
+        _ = ctrl_close.recv() => {
+            info!("console is closed, exiting now");
+            bridge.shutdown();
+            Ok(())
+        }
+    };
+    // Listeners may return before the limit is noticed.
+    if draining || bridge.is_exhausted() {
+        tokio::select! {
+            _ = bridge.drain() => (),
+            _ = ctrl_c.recv() => info!("exiting now"),
+            _ = ctrl_break.recv() => info!("exiting now"),
+            _ = ctrl_close.recv() => info!("console is closed, exiting now"),
+        }
+    }
+    // Connections left are dropped on exit, close them first so they are logged.
+    bridge.close_connections();
+    let _ = time::timeout(CLOSE_GRACE, bridge.drain()).await;
+    if let Some(path) = &cfg.ready_file {
+        let _ = fs::remove_file(path);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(cmd: &str) -> (PathBuf, Vec<String>) {
+        let startup = parse_command(cmd, "--cmd").unwrap();
+        (startup.program, startup.args)
+    }
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(
+            parse("gpg-connect-agent /bye"),
+            (PathBuf::from("gpg-connect-agent"), vec!["/bye".to_string()])
+        );
+        assert_eq!(
+            parse("  gpg-connect-agent \t /bye  "),
+            (PathBuf::from("gpg-connect-agent"), vec!["/bye".to_string()])
+        );
+        assert_eq!(
+            parse(r#""C:\Program Files (x86)\GnuPG\bin\gpg-connect-agent.exe" /bye"#),
+            (
+                PathBuf::from(r"C:\Program Files (x86)\GnuPG\bin\gpg-connect-agent.exe"),
+                vec!["/bye".to_string()]
+            )
+        );
+        assert_eq!(
+            parse(r#"wsl.exe -e sh -c "gpgconf --launch gpg-agent" """#),
+            (
+                PathBuf::from("wsl.exe"),
+                vec![
+                    "-e".to_string(),
+                    "sh".to_string(),
+                    "-c".to_string(),
+                    "gpgconf --launch gpg-agent".to_string(),
+                    String::new(),
+                ]
+            )
+        );
+        assert_eq!(
+            parse(r#"C:\"Program Files"\gpg.exe"#),
+            (PathBuf::from(r"C:\Program Files\gpg.exe"), vec![])
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_command() {
+        for cmd in ["", "   ", r#""C:\Program Files\gpg.exe /bye"#] {
+            let e = parse_command(cmd, "--cmd").unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput, "{}", cmd);
+        }
+    }
+
+    fn detached_args(argv: &[&str]) -> Vec<String> {
+        let argv: Vec<_> = argv.iter().map(|s| s.to_string()).collect();
+        build_detached_args(&argv)
+    }
+
+    #[test]
+    fn test_build_detached_args() {
+        assert_eq!(detached_args(&["gpg-bridge.exe"]), Vec::<String>::new());
+        assert_eq!(
+            detached_args(&[
+                "gpg-bridge.exe",
+                "--ssh",
+                "\\\\.\\pipe\\gpg-bridge-ssh",
+                "--detach"
+            ]),
+            ["--ssh", "\\\\.\\pipe\\gpg-bridge-ssh"]
+        );
+        assert_eq!(
+            detached_args(&[
+                "gpg-bridge.exe",
+                "--detach=true",
+                "--extra",
+                "127.0.0.1:4321",
+                "--show-console",
+                "--idle-timeout=60",
+                "--detach-keep-console",
+                "--ssh-map-name-guid",
+            ]),
+            [
+                "--extra",
+                "127.0.0.1:4321",
+                "--idle-timeout=60",
+                "--ssh-map-name-guid"
+            ]
+        );
+        // Flags only sharing the prefix are kept.
+        assert_eq!(
+            detached_args(&[
+                "gpg-bridge.exe",
+                "--detached",
+                "--detach-keep-console=false"
+            ]),
+            ["--detached"]
+        );
     }
 }
@@ -0,0 +1,119 @@
+// Watches replies from the extra socket for passphrase prompts nobody may see.
+//
+// gpg-agent inquires `PINENTRY_LAUNCHED` from the client before showing pinentry, and the
+// pinentry is shown on the machine running the agent, not the one running the client. If it
+// can't be answered there, the request hangs until pinentry times out.
+//
+// The first line is the greeting of agent, which is an ERR instead of OK if the agent rejects
+// the connection, it's logged as clients may not report it clearly.
+
+use log::{info, warn};
+
+/// Only the start of a line is needed to recognize the reply, the rest may be secret data.
+const MAX_PREFIX_LEN: usize = 64;
+
+/// Codes of libgpg-error, stored in the lower 16 bits of an error.
+const GPG_ERR_TIMEOUT: u32 = 62;
+const GPG_ERR_NO_PIN_ENTRY: u32 = 85;
+
+const LOOPBACK_HINT: &str = "set allow-loopback-pinentry in gpg-agent.conf and use \
+                             --pinentry-mode loopback on the client to enter it remotely";
+
+/// Parses the replies of one connection.
+pub(crate) struct PinentryWatch {
+    id: u64,
+    line: Vec<u8>,
+    /// Whether pinentry is launched for the pending command.
+    launched: bool,
+    /// Whether the greeting line has been seen.
+    greeted: bool,
+    /// Whether the greeting is OK.
+    welcomed: bool,
+    /// Whether any line after the greeting has been seen.
+    replied: bool,
+}
+
+impl PinentryWatch {
+    pub fn new(id: u64) -> PinentryWatch {
+        PinentryWatch {
+            id,
+            line: Vec::with_capacity(MAX_PREFIX_LEN),
+            launched: false,
+            greeted: false,
+            welcomed: false,
+            replied: false,
+        }
+    }
+
+    /// Feeds the bytes sent from agent to client.
+    pub fn feed(&mut self, data: &[u8]) {
+        for b in data {
+            if *b == b'\n' {
+                self.process_line();
+                for b in &mut self.line {
+                    *b = 0;
+                }
+                self.line.clear();
+            } else if self.line.len() < MAX_PREFIX_LEN {
+                self.line.push(*b);
+            }
+        }
+    }
+
+    /// Checks whether agent has sent nothing but an OK greeting.
+    pub fn greeting_only(&self) -> bool {
+        self.welcomed && !self.replied
+    }
+
+    fn process_line(&mut self) {
+        if self.greeted {
+            self.replied = true;
+        } else {
+            self.greeted = true;
+            self.welcomed = self.line.starts_with(b"OK");
+            if self.line.starts_with(b"ERR ") {
+                // Error lines only have the code and description, no secret.
+                warn!(
+                    "connection {}: agent rejected the connection: {}",
+                    self.id,
+                    String::from_utf8_lossy(&self.line).trim_end()
+                );
+                return;
+            }
+        }
+        if self.line.starts_with(b"INQUIRE PINENTRY_LAUNCHED") {
+            self.launched = true;
+            info!(
+                "connection {}: agent launched pinentry on this machine, {}",
+                self.id, LOOPBACK_HINT
+            );
+            return;
+        }
+        if self.line.starts_with(b"OK") {
+            self.launched = false;
+            return;
+        }
+        let code = match self.line.strip_prefix(b"ERR ") {
+            Some(rest) => rest
+                .iter()
+                .take_while(|b| b.is_ascii_digit())
+                .fold(0u32, |c, b| {
+                    c.wrapping_mul(10).wrapping_add((b - b'0') as u32)
+                }),
+            None => return,
+        };
+        match code & 0xffff {
+            GPG_ERR_NO_PIN_ENTRY => warn!(
+                "connection {}: agent has no pinentry to ask for passphrase, set pinentry-program \
+                 in gpg-agent.conf, or {}",
+                self.id, LOOPBACK_HINT
+            ),
+            GPG_ERR_TIMEOUT if self.launched => warn!(
+                "connection {}: pinentry timed out, it may be shown where nobody sees it, {}",
+                self.id, LOOPBACK_HINT
+            ),
+            _ => (),
+        }
+        self.launched = false;
+    }
+}
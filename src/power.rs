@@ -0,0 +1,48 @@
+// Notifies when the system resumes from sleep.
+//
+// gpg-agent may be restarted while the system sleeps, leaving cached ports and nonces
+// stale, so they should be loaded again before the next connection.
+
+use crate::util::other_error;
+use std::ffi::c_void;
+use std::{io, ptr};
+use tokio::sync::mpsc;
+use windows::Win32::Foundation::HANDLE;
+use windows::Win32::System::Power::{
+    PowerRegisterSuspendResumeNotification, DEVICE_NOTIFY_SUBSCRIBE_PARAMETERS,
+};
+use windows::Win32::UI::WindowsAndMessaging::{DEVICE_NOTIFY_CALLBACK, PBT_APMRESUMEAUTOMATIC};
+
+unsafe extern "system" fn on_power_event(
+    context: *const c_void,
+    ty: u32,
+    _setting: *const c_void,
+) -> u32 {
+    // It's sent for every resume, PBT_APMRESUMESUSPEND only follows it if user is present.
+    if ty == PBT_APMRESUMEAUTOMATIC {
+        let tx = &*(context as *const mpsc::UnboundedSender<()>);
+        let _ = tx.send(());
+    }
+    0
+}
+
+/// Registers for resume notifications, an event is received every time the system resumes
+/// from sleep or hibernation.
+pub(crate) fn resume_events() -> io::Result<mpsc::UnboundedReceiver<()>> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    // Notifications are received for the rest of the process, so both are leaked.
+    let params = Box::into_raw(Box::new(DEVICE_NOTIFY_SUBSCRIBE_PARAMETERS {
+        Callback: Some(on_power_event),
+        Context: Box::into_raw(Box::new(tx)) as *mut c_void,
+    }));
+    let mut handle = ptr::null_mut();
+    unsafe {
+        PowerRegisterSuspendResumeNotification(
+            DEVICE_NOTIFY_CALLBACK,
+            HANDLE(params as isize),
+            &mut handle,
+        )
+    }
+    .map_err(|e| other_error(format!("failed to register power notification: {:?}", e)))?;
+    Ok(rx)
+}
@@ -0,0 +1,110 @@
+// Forwards only informational Assuan commands to the agent, for health checks that should
+// not be able to use any key.
+//
+// Assuan is request and response: agent greets, then every command is answered by lines
+// ending with "OK" or "ERR". Commands that may inquire data from the client are never
+// forwarded, so client and agent simply take turns.
+
+use crate::util::{other_error, SplitStream};
+use crate::AgentSideError;
+use log::debug;
+use std::io;
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+use tokio::net::TcpStream;
+
+/// Commands forwarded to agent, others are rejected.
+const ALLOWED_COMMANDS: &[&[u8]] = &[b"GETINFO", b"NOP", b"BYE"];
+
+/// Assuan limits a line to 1000 bytes, including the line feed.
+const MAX_LINE_LEN: usize = 1000;
+
+/// GPG_ERR_FORBIDDEN.
+const FORBIDDEN: &[u8] = b"ERR 251 Forbidden <gpg-bridge>\n";
+
+/// Reads a line including its line feed, returns false at EOF.
+async fn read_line(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    line: &mut Vec<u8>,
+) -> io::Result<bool> {
+    line.clear();
+    let limit = MAX_LINE_LEN as u64;
+    reader.take(limit).read_until(b'\n', line).await?;
+    if line.is_empty() {
+        return Ok(false);
+    }
+    if !line.ends_with(b"\n") {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "line is too long or truncated",
+        ));
+    }
+    Ok(true)
+}
+
+/// Relays lines from agent to client until the response ends.
+///
+/// Errors of reading from agent are wrapped in `AgentSideError`.
+async fn relay_response(
+    agent: &mut (impl AsyncBufRead + Unpin),
+    client: &mut (impl AsyncWrite + Unpin),
+    line: &mut Vec<u8>,
+) -> io::Result<()> {
+    loop {
+        match read_line(agent, line).await {
+            Ok(true) => (),
+            Ok(false) => return Err(AgentSideError::wrap(io::ErrorKind::UnexpectedEof.into())),
+            Err(e) => return Err(AgentSideError::wrap(e)),
+        }
+        if line.starts_with(b"INQUIRE") {
+            let e = other_error("agent inquires data in probe mode".to_string());
+            return Err(AgentSideError::wrap(e));
+        }
+        client.write_all(line).await?;
+        if line.starts_with(b"OK") || line.starts_with(b"ERR") {
+            return Ok(());
+        }
+    }
+}
+
+/// Forwards the allowed commands from `from` to agent `to`, and answers others with ERR.
+pub(crate) async fn delegate_probe(
+    id: u64,
+    mut from: impl SplitStream,
+    mut to: TcpStream,
+) -> io::Result<()> {
+    let (source_read, mut source_write) = from.split_rw();
+    let (target_read, mut target_write) = to.split();
+    let mut source = BufReader::new(source_read);
+    let mut target = BufReader::new(target_read);
+    let mut line = Vec::with_capacity(MAX_LINE_LEN);
+    // Greeting.
+    relay_response(&mut target, &mut source_write, &mut line).await?;
+    while read_line(&mut source, &mut line).await? {
+        let cmd = line
+            .split(|b| b.is_ascii_whitespace())
+            .next()
+            .unwrap_or_default()
+            .to_ascii_uppercase();
+        if !ALLOWED_COMMANDS.contains(&&*cmd) {
+            debug!(
+                "connection {}: reject {} in probe mode",
+                id,
+                String::from_utf8_lossy(&cmd)
+            );
+            source_write.write_all(FORBIDDEN).await?;
+            continue;
+        }
+        target_write
+            .write_all(&line)
+            .await
+            .map_err(AgentSideError::wrap)?;
+        relay_response(&mut target, &mut source_write, &mut line).await?;
+        if cmd == b"BYE" {
+            break;
+        }
+    }
+    debug!("connection {} (probe) closed", id);
+    Ok(())
+}
@@ -1,18 +1,28 @@
 // Refer https://github.com/gpg/gnupg/blob/master/agent/gpg-agent.c#L2528
 
-use crate::util::other_error;
+use crate::util::{loggable, other_error, ScrubBuf};
 use core::slice;
-use log::trace;
+use log::{info, trace, warn};
 use std::ffi::c_void;
 use std::io::{self, Error, ErrorKind};
 use std::pin::Pin;
 use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use std::{error, fmt};
 use tokio::io::AsyncRead;
 use tokio::io::AsyncReadExt;
 use tokio::sync::Semaphore;
 use tokio::sync::SemaphorePermit;
+use tokio::task::{self, JoinHandle};
+use tokio::time::{self, Instant};
 use windows::core::PCSTR;
 use windows::Win32::Foundation::{CloseHandle, HANDLE, INVALID_HANDLE_VALUE, LPARAM, WPARAM};
+use windows::Win32::Security::Cryptography::{
+    BCryptGenRandom, BCryptHash, BCRYPTGENRANDOM_FLAGS, BCRYPT_RNG_ALG_HANDLE,
+    BCRYPT_SHA256_ALG_HANDLE,
+};
 use windows::Win32::System::DataExchange::COPYDATASTRUCT;
 use windows::Win32::System::Memory::{
     CreateFileMappingA, MapViewOfFile, UnmapViewOfFile, FILE_MAP_ALL_ACCESS,
@@ -20,30 +30,43 @@ use windows::Win32::System::Memory::{
 };
 use windows::Win32::UI::WindowsAndMessaging::{FindWindowA, SendMessageA, WM_COPYDATA};
 
-/// A magic value used with WM_COPYDATA.
-const PUTTY_IPC_MAGIC: usize = 0x804e50ba;
+/// A magic value used with WM_COPYDATA by Pageant and gpg-agent.
+pub const PUTTY_IPC_MAGIC: usize = 0x804e50ba;
+static PAGEANT_MAGIC: OnceLock<usize> = OnceLock::new();
 static FILE_MAP_NAME: &str = "gpg_bridge";
 static PAGEANT_WINDOW_NAME: &str = "Pageant\0";
+static GUID_MAP_NAMES: AtomicBool = AtomicBool::new(false);
 
 /// To avoid surprises we limit the size of the mapped IPC file to this
 /// value.  Putty currently (0.62) uses 8k, thus 16k should be enough
 /// for the foreseeable future.  */
+///
+/// Messages are relayed as is, including the 4 bytes length prefix, so any
+/// message up to this size can pass through. It's enough for adding an RSA
+/// 8192 private key (about 4.5k) with lifetime and confirmation constraints.
 pub const PUTTY_IPC_MAXLEN: usize = 16384;
 
-static CONCURRENCY: Semaphore = Semaphore::const_new(4);
+/// Maximum number of ssh requests that can be processed at the same time. Every request
+/// takes a bit in the token bitmap to name its file mapping if there is a free one.
+const SSH_CONCURRENCY: usize = 4;
+const _: () = assert!(SSH_CONCURRENCY <= u8::BITS as usize);
+
+static CONCURRENCY: Semaphore = Semaphore::const_new(SSH_CONCURRENCY);
 static TOKEN: parking_lot::Mutex<u8> = parking_lot::const_mutex(0);
 
-fn find_available_token() -> u8 {
+/// Takes a free bit of the token bitmap. All bits can be taken even if a permit is acquired,
+/// as requests timed out keep their bits until agent returns.
+fn find_available_token() -> Option<u8> {
     let mut token = TOKEN.lock();
     let mut mask = 1;
-    for _ in 0..4 {
+    for _ in 0..SSH_CONCURRENCY {
         if *token & mask == 0 {
             *token |= mask;
-            return mask;
+            return Some(mask);
         }
         mask <<= 1;
     }
-    unreachable!()
+    None
 }
 
 fn release_token(mask: u8) {
@@ -51,130 +74,483 @@ fn release_token(mask: u8) {
     *token &= !mask;
 }
 
+/// Sets the magic sent with WM_COPYDATA, it can only be set once before any ssh request is
+/// sent.
+///
+/// Agents that are compatible with Pageant may expect a different value than
+/// `PUTTY_IPC_MAGIC`.
+pub fn set_pageant_magic(magic: usize) -> io::Result<()> {
+    PAGEANT_MAGIC
+        .set(magic)
+        .map_err(|_| Error::new(ErrorKind::AlreadyExists, "pageant magic is already set"))
+}
+
+/// Names file mappings by a random GUID instead of the token of the request, so they
+/// never collide with mappings of other processes.
+pub fn set_guid_map_names(enabled: bool) {
+    GUID_MAP_NAMES.store(enabled, Ordering::Relaxed);
+}
+
+/// Generates a random (version 4) GUID in the registry format without braces.
+fn random_guid() -> io::Result<String> {
+    let mut b = [0u8; 16];
+    unsafe { BCryptGenRandom(BCRYPT_RNG_ALG_HANDLE, &mut b, BCRYPTGENRANDOM_FLAGS(0)) }
+        .ok()
+        .map_err(|e| other_error(format!("failed to generate guid: {:?}", e)))?;
+    b[6] = (b[6] & 0x0f) | 0x40;
+    b[8] = (b[8] & 0x3f) | 0x80;
+    let hex = |b: &[u8]| b.iter().map(|v| format!("{:02x}", v)).collect::<String>();
+    Ok(format!(
+        "{}-{}-{}-{}-{}",
+        hex(&b[..4]),
+        hex(&b[4..6]),
+        hex(&b[6..8]),
+        hex(&b[8..10]),
+        hex(&b[10..])
+    ))
+}
+
+/// Gets the null terminated name of file mapping for the request holding `mask`.
+fn map_name(mask: u8) -> io::Result<String> {
+    if GUID_MAP_NAMES.load(Ordering::Relaxed) {
+        guid_map_name()
+    } else {
+        Ok(format!("{}-{}\0", FILE_MAP_NAME, mask))
+    }
+}
+
+/// Gets a null terminated name of file mapping that is never used before.
+///
+/// A GUID name is 48 bytes including the null, far below MAX_PATH that
+/// `CreateFileMappingA` accepts.
+fn guid_map_name() -> io::Result<String> {
+    Ok(format!("{}-{}\0", FILE_MAP_NAME, random_guid()?))
+}
+
+/// Notifies agent to process the request stored in the file mapping `name`.
+///
+/// It blocks until agent replies, so should not be called on async threads. Fails with
+/// `ErrorKind::NotFound` if agent is not running, and `ErrorKind::ConnectionRefused` if agent
+/// fails to process the request, like when it can't open or validate the mapping.
+fn send_request(name: &str) -> io::Result<()> {
+    let mut name = name.to_owned();
+    let pageant_window_name = PCSTR::from_raw(PAGEANT_WINDOW_NAME.as_ptr());
+    let win = unsafe { FindWindowA(pageant_window_name, pageant_window_name) };
+    if win.0 == 0 {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!("can't contact gpg agent: {}", Error::last_os_error()),
+        ));
+    }
+    let copy_data = COPYDATASTRUCT {
+        dwData: *PAGEANT_MAGIC.get_or_init(|| PUTTY_IPC_MAGIC),
+        cbData: name.len() as u32,
+        lpData: name.as_mut_ptr() as *mut c_void,
+    };
+    let res = unsafe {
+        SendMessageA(
+            win,
+            WM_COPYDATA,
+            WPARAM::default(),
+            LPARAM((&copy_data) as *const _ as _),
+        )
+    };
+    if res.0 == 0 {
+        return Err(Error::new(
+            ErrorKind::ConnectionRefused,
+            format!("agent rejects the request: {}", Error::last_os_error()),
+        ));
+    }
+    Ok(())
+}
+
+/// Notifies agent of the request in the file mapping of the given name, see `send_request`.
+type Transport = Arc<dyn Fn(&str) -> io::Result<()> + Send + Sync>;
+
+/// A complete SSH_AGENT_FAILURE frame, including the length prefix.
+pub const SSH_AGENT_FAILURE_FRAME: [u8; 5] = [0, 0, 0, 1, 5];
+
+/// A request or response exceeds the size of the mapped IPC file.
+#[derive(Debug)]
+pub struct FrameTooLarge {
+    kind: &'static str,
+    len: usize,
+    limit: usize,
+}
+
+impl FrameTooLarge {
+    /// Checks the length of a frame, including its 4 bytes length prefix.
+    ///
+    /// The whole frame lives in the mapped file, so a frame of exactly `limit` bytes, whose
+    /// payload is `limit - 4` bytes, is the largest one allowed. Requests and responses share
+    /// the same bound.
+    fn check(kind: &'static str, len: usize, limit: usize) -> io::Result<()> {
+        if len <= limit {
+            return Ok(());
+        }
+        Err(io::Error::new(
+            ErrorKind::InvalidData,
+            FrameTooLarge { kind, len, limit },
+        ))
+    }
+
+    /// Checks if `e` is caused by a frame that is too large.
+    pub fn is(e: &io::Error) -> bool {
+        e.get_ref().is_some_and(|e| e.is::<FrameTooLarge>())
+    }
+}
+
+impl fmt::Display for FrameTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} too large: {} > {}", self.kind, self.len, self.limit)
+    }
+}
+
+impl error::Error for FrameTooLarge {}
+
+/// No request is received within the idle timeout, see `Handler::set_idle_timeout`.
+///
+/// Unlike a request timing out, there is no request to reply to.
+#[derive(Debug)]
+pub struct IdleTimeout(Duration);
+
+impl IdleTimeout {
+    /// Checks if `e` is caused by a connection staying idle.
+    pub fn is(e: &io::Error) -> bool {
+        e.get_ref().is_some_and(|e| e.is::<IdleTimeout>())
+    }
+}
+
+impl fmt::Display for IdleTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "no ssh request is received in {:?}", self.0)
+    }
+}
+
+impl error::Error for IdleTimeout {}
+
+const SSH_AGENTC_REQUEST_IDENTITIES: u8 = 11;
+const SSH_AGENT_IDENTITIES_ANSWER: u8 = 12;
+const SSH_AGENTC_SIGN_REQUEST: u8 = 13;
+
+/// A key offered by ssh agent.
+pub struct Identity {
+    pub key_type: String,
+    /// SHA256 fingerprint of the key, in the format of `ssh-add -l`.
+    pub fingerprint: String,
+    pub comment: String,
+}
+
+fn truncated_identities() -> io::Error {
+    Error::new(ErrorKind::InvalidData, "truncated identities answer")
+}
+
+fn read_u32(buf: &mut &[u8]) -> io::Result<u32> {
+    if buf.len() < 4 {
+        return Err(truncated_identities());
+    }
+    let (n, rest) = buf.split_at(4);
+    *buf = rest;
+    Ok(u32::from_be_bytes([n[0], n[1], n[2], n[3]]))
+}
+
+fn read_string<'a>(buf: &mut &'a [u8]) -> io::Result<&'a [u8]> {
+    let len = read_u32(buf)? as usize;
+    if buf.len() < len {
+        return Err(truncated_identities());
+    }
+    let (s, rest) = buf.split_at(len);
+    *buf = rest;
+    Ok(s)
+}
+
+fn push_base64(out: &mut String, data: &[u8]) {
+    const TABLE: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    for chunk in data.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+        // No padding, n bytes take n + 1 characters.
+        for i in 0..=chunk.len() {
+            out.push(TABLE[(n >> (18 - 6 * i)) as usize & 63] as char);
+        }
+    }
+}
+
+/// Gets the fingerprint of key blob `key`, in the same format as `ssh-keygen -l`.
+fn fingerprint(key: &[u8]) -> io::Result<String> {
+    let mut digest = [0; 32];
+    unsafe { BCryptHash(BCRYPT_SHA256_ALG_HANDLE, None, key, &mut digest) }
+        .ok()
+        .map_err(|e| other_error(format!("failed to hash key: {:?}", e)))?;
+    let mut s = String::from("SHA256:");
+    push_base64(&mut s, &digest);
+    Ok(s)
+}
+
+/// Logs which key signs in request `body`, the data to sign is never logged.
+fn log_sign_request(id: u64, mut body: &[u8]) {
+    let res = read_string(&mut body).and_then(|key| {
+        let mut blob = key;
+        let key_type = String::from_utf8_lossy(read_string(&mut blob)?).into_owned();
+        Ok((key_type, fingerprint(key)?))
+    });
+    match res {
+        Ok((key_type, fp)) => info!("connection {}: sign with {} key {}", id, key_type, fp),
+        Err(e) => warn!("connection {}: unparseable sign request: {}", id, e),
+    }
+}
+
+/// Asks agent for the keys it offers, the same way forwarded clients do.
+pub async fn request_identities() -> io::Result<Vec<Identity>> {
+    let mut handler = Handler::new(None).await?;
+    let req = [0, 0, 0, 1, SSH_AGENTC_REQUEST_IDENTITIES];
+    let mut reader: Pin<Box<dyn AsyncRead + Send + '_>> = Box::pin(&req[..]);
+    let resp = match handler.process_one(&mut reader).await? {
+        Some(resp) => resp,
+        None => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "no reply to identities request",
+            ))
+        }
+    };
+    parse_identities(resp)
+}
+
+/// Parses the reply to an identities request, including the 4 bytes length prefix.
+fn parse_identities(resp: &[u8]) -> io::Result<Vec<Identity>> {
+    let mut buf = &resp[4..];
+    match buf.first() {
+        Some(&SSH_AGENT_IDENTITIES_ANSWER) => buf = &buf[1..],
+        t => {
+            return Err(other_error(format!(
+                "unexpected reply to identities request: {:?}",
+                t
+            )))
+        }
+    }
+    let count = read_u32(&mut buf)?;
+    let mut identities = Vec::new();
+    for _ in 0..count {
+        let key = read_string(&mut buf)?;
+        let mut blob = key;
+        let key_type = read_string(&mut blob)?;
+        let comment = read_string(&mut buf)?;
+        identities.push(Identity {
+            key_type: String::from_utf8_lossy(key_type).into_owned(),
+            fingerprint: fingerprint(key)?,
+            comment: String::from_utf8_lossy(comment).into_owned(),
+        });
+    }
+    Ok(identities)
+}
+
+async fn with_deadline<F: std::future::Future>(
+    deadline: Option<Instant>,
+    f: F,
+) -> io::Result<F::Output> {
+    match deadline {
+        Some(deadline) => time::timeout_at(deadline, f)
+            .await
+            .map_err(|_| Error::new(ErrorKind::TimedOut, "ssh request timed out")),
+        None => Ok(f.await),
+    }
+}
+
+/// Creates file mapping `name` and maps the whole view.
+fn create_mapping(name: &str) -> io::Result<(HANDLE, MEMORY_MAPPED_VIEW_ADDRESS)> {
+    let handle = unsafe {
+        CreateFileMappingA(
+            INVALID_HANDLE_VALUE,
+            None,
+            PAGE_READWRITE,
+            0,
+            PUTTY_IPC_MAXLEN as u32,
+            PCSTR::from_raw(name.as_ptr()),
+        )
+    };
+    let handle = match handle {
+        Ok(h) => h,
+        Err(e) => {
+            return Err(other_error(format!(
+                "failed to create memory mapping: {e:?}"
+            )));
+        }
+    };
+    let view = unsafe { MapViewOfFile(handle, FILE_MAP_ALL_ACCESS, 0, 0, PUTTY_IPC_MAXLEN) };
+    if view.Value.is_null() {
+        let res = unsafe { CloseHandle(handle) };
+        if let Err(e) = res {
+            return Err(other_error(format!(
+                "can't map view of memory and handle can't be closed: {e:?}"
+            )));
+        } else {
+            return Err(other_error("can't map view of memory".to_string()));
+        }
+    }
+    Ok((handle, view))
+}
+
+/// Relays ssh requests of one connection to agent through a file mapping.
+///
+/// Requests and responses may carry secrets like private keys being added, so the whole view
+/// is zeroed before it's unmapped, when the handler is dropped or the mapping is replaced.
+/// As the mapping is only named and held by the handler and the agent, nothing of a
+/// connection lingers in it once both close their handles. The only exception is a request
+/// that times out: agent may write its response after the view is zeroed, which lives in
+/// the mapping until agent closes its handle and the mapping is destroyed.
 pub struct Handler {
     handle: HANDLE,
     view: MEMORY_MAPPED_VIEW_ADDRESS,
     limit: usize,
     mask: u8,
     name: String,
-    _permit: SemaphorePermit<'static>,
+    permit: Option<SemaphorePermit<'static>>,
+    transport: Transport,
+    /// Deadline of a request since its length is received.
+    timeout: Option<Duration>,
+    /// How long to wait for the next request.
+    idle_timeout: Option<Duration>,
+    /// Bytes of complete requests read from client, see `received`.
     received: usize,
+    /// Bytes of responses returned to caller, see `replied`.
     replied: usize,
+    /// Connection id to log keys of sign requests with.
+    log_signs: Option<u64>,
+    /// Time of reading the body and waiting for agent of the last request.
+    last_timing: (Duration, Duration),
 }
 
 unsafe impl Send for Handler {}
 
 impl Handler {
-    pub async fn new() -> io::Result<Handler> {
+    pub async fn new(timeout: Option<Duration>) -> io::Result<Handler> {
         let permit = CONCURRENCY.acquire().await.unwrap();
-        let mask = find_available_token();
-        let name = format!("{}-{}\0", FILE_MAP_NAME, mask);
-        let handle = unsafe {
-            CreateFileMappingA(
-                INVALID_HANDLE_VALUE,
-                None,
-                PAGE_READWRITE,
-                0,
-                PUTTY_IPC_MAXLEN as u32,
-                PCSTR::from_raw(name.as_ptr()),
-            )
-        };
-        let handle = match handle {
-            Ok(h) => h,
+        let token = find_available_token();
+        // Names of GUID never collide with the ones of tokens, 0 holds no bit.
+        let name = token.map_or_else(guid_map_name, map_name);
+        let mask = token.unwrap_or(0);
+        let mapping = name.and_then(|name| Ok((create_mapping(&name)?, name)));
+        let ((handle, view), name) = match mapping {
+            Ok(mapping) => mapping,
             Err(e) => {
                 release_token(mask);
-                return Err(other_error(format!(
-                    "failed to create memory mapping: {e:?}"
-                )));
+                return Err(e);
             }
         };
-        let view = unsafe { MapViewOfFile(handle, FILE_MAP_ALL_ACCESS, 0, 0, PUTTY_IPC_MAXLEN) };
-        if view.Value.is_null() {
-            let res = unsafe { CloseHandle(handle) };
-            release_token(mask);
-            if let Err(e) = res {
-                return Err(other_error(format!(
-                    "can't map view of memory and handle can't be closed: {e:?}"
-                )));
-            } else {
-                return Err(other_error("can't map view of memory".to_string()));
-            }
-        }
         Ok(Handler {
             handle,
             view,
             limit: PUTTY_IPC_MAXLEN,
             mask,
             name,
-            _permit: permit,
+            permit: Some(permit),
+            transport: Arc::new(send_request),
+            timeout,
+            idle_timeout: None,
             received: 0,
             replied: 0,
+            log_signs: None,
+            last_timing: (Duration::ZERO, Duration::ZERO),
         })
     }
 
+    /// Gets the time of reading the body and waiting for agent of the last request.
+    pub fn last_timing(&self) -> (Duration, Duration) {
+        self.last_timing
+    }
+
+    /// Fails `process_one` if the next request doesn't start in `timeout`.
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.idle_timeout = timeout;
+    }
+
+    /// Logs the key used by every sign request at info level, along with connection `id`.
+    pub fn log_signs(&mut self, id: u64) {
+        self.log_signs = Some(id);
+    }
+
+    /// Reads the length of the frame in view, including the 4 bytes big endian length prefix.
+    fn read_frame_len(&self) -> usize {
+        let len = u32::from_be(unsafe { (self.view.Value as *const u32).read_unaligned() });
+        (len as usize).saturating_add(4)
+    }
+
     pub async fn process_one(
         &mut self,
         reader: &mut Pin<Box<dyn AsyncRead + Send + '_>>,
     ) -> io::Result<Option<&[u8]>> {
         let len_bytes = unsafe { slice::from_raw_parts_mut(self.view.Value as *mut u8, 4) };
-        if let Err(e) = reader.read_exact(len_bytes).await {
+        let read_len = reader.read_exact(len_bytes);
+        let res = match self.idle_timeout {
+            Some(t) => time::timeout(t, read_len)
+                .await
+                .map_err(|_| Error::new(ErrorKind::TimedOut, IdleTimeout(t)))?,
+            None => read_len.await,
+        };
+        if let Err(e) = res {
             if e.kind() == ErrorKind::UnexpectedEof {
                 return Ok(None);
             } else {
                 return Err(e);
             }
         }
-        let len =
-            u32::from_be(unsafe { (self.view.Value as *mut u32).read_unaligned() }) as usize + 4;
-        if len >= self.limit {
-            return Err(other_error(format!(
-                "message too large: {} >= {}",
-                len + 4,
-                self.limit
-            )));
-        }
-        self.received += len;
+        let len = self.read_frame_len();
+        FrameTooLarge::check("message", len, self.limit)?;
+        // Waiting for next request is not limited, clients can keep connections idle.
+        let start = Instant::now();
+        let deadline = self.timeout.map(|t| start + t);
         let req =
             unsafe { slice::from_raw_parts_mut((self.view.Value as *mut u8).add(4), len - 4) };
-        reader.read_exact(req).await?;
-        trace!("recv request {:?}", String::from_utf8_lossy(req));
-        let pageant_window_name = PCSTR::from_raw(PAGEANT_WINDOW_NAME.as_ptr());
-        let win = unsafe { FindWindowA(pageant_window_name, pageant_window_name) };
-        if win.0 == 0 {
-            return Err(other_error(format!(
-                "can't contact gpg agent: {}",
-                Error::last_os_error()
-            )));
+        with_deadline(deadline, reader.read_exact(req)).await??;
+        self.received += len;
+        trace!("recv request {}", loggable(req));
+        let read = start.elapsed();
+        if let Some(id) = self.log_signs {
+            if req.first() == Some(&SSH_AGENTC_SIGN_REQUEST) {
+                log_sign_request(id, &req[1..]);
+            }
         }
-        let copy_data = COPYDATASTRUCT {
-            dwData: PUTTY_IPC_MAGIC,
-            cbData: self.name.len() as u32,
-            lpData: self.name.as_mut_ptr() as *mut c_void,
-        };
-        let res = unsafe {
-            SendMessageA(
-                win,
-                WM_COPYDATA,
-                WPARAM::default(),
-                LPARAM((&copy_data) as *const _ as _),
-            )
-        };
-        if res.0 == 0 {
-            return Err(other_error(format!(
-                "failed to send message: {}",
-                Error::last_os_error()
-            )));
+        // Agent may declare a response longer than it actually writes. Clear everything after
+        // the request, so an under-filled response can only contain zeros or the request from
+        // the same client, never stale data of previous requests.
+        unsafe {
+            ptr::write_bytes((self.view.Value as *mut u8).add(len), 0, self.limit - len);
         }
-
-        let len =
-            u32::from_be(unsafe { (self.view.Value as *mut u32).read_unaligned() }) as usize + 4;
-        if len > self.limit {
-            return Err(other_error(format!(
-                "response too large: {} > {}",
-                len + 4,
-                self.limit
-            )));
+        // Both FindWindowA and SendMessageA are blocking calls, the latter doesn't return until
+        // agent finishes the request, which can take a while if user interaction is required.
+        // Run them on blocking thread to keep reactor responsive. Only the owned mapping name is
+        // moved, the view is not touched until the round trip is finished.
+        let mut remapped = false;
+        loop {
+            let (name, transport) = (self.name.clone(), self.transport.clone());
+            let mut round_trip = task::spawn_blocking(move || transport(&name));
+            let res = match with_deadline(deadline, &mut round_trip).await {
+                Ok(res) => res,
+                Err(e) => {
+                    self.release_after(round_trip);
+                    return Err(e);
+                }
+            };
+            match res.map_err(|e| other_error(format!("failed to wait for agent reply: {e:?}")))? {
+                Ok(()) => break,
+                // Agent can't use the mapping, which may be stale, retry once with a fresh
+                // one. Other failures, like agent not running, are not fixed by a new
+                // mapping. Agent doesn't process a request it rejects, so it's not repeated.
+                Err(e) if e.kind() == ErrorKind::ConnectionRefused && !remapped => {
+                    warn!("failed to send request: {}, retry with new mapping", e);
+                    self.remap(len)?;
+                    remapped = true;
+                }
+                Err(e) => return Err(e),
+            }
         }
+
+        self.last_timing = (read, start.elapsed() - read);
+        let len = self.read_frame_len();
+        FrameTooLarge::check("response", len, self.limit)?;
         self.replied += len;
         unsafe {
             Ok(Some(slice::from_raw_parts(
@@ -184,10 +560,65 @@ impl Handler {
         }
     }
 
+    /// Replaces the mapping with a new one of a fresh name, the first `len` bytes of request
+    /// are kept.
+    ///
+    /// Reusing the name may open the same stale object again, if agent or anyone else still
+    /// holds it.
+    fn remap(&mut self, len: usize) -> io::Result<()> {
+        let name = guid_map_name()?;
+        // Zeroed when dropped, the request may carry a private key.
+        let mut req = ScrubBuf::new(len);
+        unsafe {
+            ptr::copy_nonoverlapping(self.view.Value as *const u8, req.0.as_mut_ptr(), len);
+            self.unmap();
+        }
+        let (handle, view) = create_mapping(&name)?;
+        unsafe {
+            ptr::copy_nonoverlapping(req.0.as_ptr(), view.Value as *mut u8, len);
+            ptr::write_bytes((view.Value as *mut u8).add(len), 0, self.limit - len);
+        }
+        self.handle = handle;
+        self.view = view;
+        self.name = name;
+        Ok(())
+    }
+
+    /// Zeroes and unmaps the view, it's safe to call more than once.
+    unsafe fn unmap(&mut self) {
+        if self.view.Value.is_null() {
+            return;
+        }
+        // The view is passed to agent and unmapped by FFI calls, so the writes can't be
+        // optimized away as dead stores.
+        ptr::write_bytes(self.view.Value as *mut u8, 0, self.limit);
+        let _ = UnmapViewOfFile(self.view);
+        let _ = CloseHandle(self.handle);
+        self.view = MEMORY_MAPPED_VIEW_ADDRESS {
+            Value: ptr::null_mut(),
+        };
+    }
+
+    /// Agent may still write to the file mapping after timeout, so the name can't be reused
+    /// until agent returns. The permit is released right away, otherwise requests stuck in
+    /// agent would block all new ones.
+    fn release_after(&mut self, round_trip: JoinHandle<io::Result<()>>) {
+        let mask = std::mem::replace(&mut self.mask, 0);
+        self.permit = None;
+        tokio::spawn(async move {
+            let _ = round_trip.await;
+            release_token(mask);
+        });
+    }
+
+    /// Gets the bytes of requests received on the wire, including the 4 bytes length prefix
+    /// of every request. A request is only counted after it's read completely.
     pub fn received(&self) -> usize {
         self.received
     }
 
+    /// Gets the bytes of responses returned by `process_one`, including the 4 bytes length
+    /// prefix of every response, which is what the caller writes to the wire.
     pub fn replied(&self) -> usize {
         self.replied
     }
@@ -195,11 +626,309 @@ impl Handler {
 
 impl Drop for Handler {
     fn drop(&mut self) {
-        unsafe {
-            ptr::write_bytes(self.view.Value as *mut u8, 0, self.limit);
-            let _ = UnmapViewOfFile(self.view);
-            let _ = CloseHandle(self.handle);
-        }
+        unsafe { self.unmap() };
         release_token(self.mask);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use windows::Win32::System::Memory::OpenFileMappingA;
+
+    /// A view of a mapping opened by name, like agent does. It keeps the mapping alive until
+    /// dropped.
+    struct AgentView(HANDLE, MEMORY_MAPPED_VIEW_ADDRESS);
+
+    unsafe impl Send for AgentView {}
+
+    impl AgentView {
+        fn open(name: &str) -> io::Result<AgentView> {
+            let name = PCSTR::from_raw(name.as_ptr());
+            let handle = unsafe { OpenFileMappingA(FILE_MAP_ALL_ACCESS.0, false, name) }
+                .map_err(|e| other_error(format!("failed to open mapping: {:?}", e)))?;
+            let view =
+                unsafe { MapViewOfFile(handle, FILE_MAP_ALL_ACCESS, 0, 0, PUTTY_IPC_MAXLEN) };
+            assert!(!view.Value.is_null());
+            Ok(AgentView(handle, view))
+        }
+
+        fn bytes(&mut self) -> &mut [u8] {
+            unsafe { slice::from_raw_parts_mut(self.1.Value as *mut u8, PUTTY_IPC_MAXLEN) }
+        }
+    }
+
+    impl Drop for AgentView {
+        fn drop(&mut self) {
+            unsafe {
+                let _ = UnmapViewOfFile(self.1);
+                let _ = CloseHandle(self.0);
+            }
+        }
+    }
+
+    /// Opens the mapping `name` and passes its view to `f`.
+    fn with_view(name: &str, f: impl FnOnce(&mut [u8])) -> io::Result<()> {
+        f(AgentView::open(name)?.bytes());
+        Ok(())
+    }
+
+    /// Sets a transport that fails the first `failures` requests with `kind`, and answers the
+    /// others with the request itself. Returns the names of mappings in requests.
+    fn fake_transport(
+        handler: &mut Handler,
+        failures: usize,
+        kind: ErrorKind,
+    ) -> Arc<Mutex<Vec<String>>> {
+        let names = Arc::new(Mutex::new(vec![]));
+        let seen = names.clone();
+        handler.transport = Arc::new(move |name: &str| {
+            let mut names = seen.lock().unwrap();
+            names.push(name.to_owned());
+            if names.len() <= failures {
+                return Err(Error::new(kind, "fake failure"));
+            }
+            with_view(name, |_| ())
+        });
+        names
+    }
+
+    async fn process(handler: &mut Handler, req: &[u8]) -> io::Result<Vec<u8>> {
+        let mut reader: Pin<Box<dyn AsyncRead + Send + '_>> = Box::pin(req);
+        Ok(handler.process_one(&mut reader).await?.unwrap().to_vec())
+    }
+
+    const REQUEST_IDENTITIES: [u8; 5] = [0, 0, 0, 1, SSH_AGENTC_REQUEST_IDENTITIES];
+    const SSH_AGENT_SUCCESS_FRAME: [u8; 5] = [0, 0, 0, 1, 6];
+
+    #[tokio::test]
+    async fn test_remap_rejected_request() {
+        let mut handler = Handler::new(None).await.unwrap();
+        let names = fake_transport(&mut handler, 1, ErrorKind::ConnectionRefused);
+        let resp = process(&mut handler, &REQUEST_IDENTITIES).await.unwrap();
+        // The request is carried over to the new mapping.
+        assert_eq!(resp, REQUEST_IDENTITIES);
+        let names = names.lock().unwrap();
+        assert_eq!(names.len(), 2);
+        assert_ne!(names[0], names[1]);
+    }
+
+    #[tokio::test]
+    async fn test_remap_only_once() {
+        let mut handler = Handler::new(None).await.unwrap();
+        let names = fake_transport(&mut handler, 2, ErrorKind::ConnectionRefused);
+        let e = process(&mut handler, &REQUEST_IDENTITIES)
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::ConnectionRefused);
+        assert_eq!(names.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_no_remap_without_agent() {
+        let mut handler = Handler::new(None).await.unwrap();
+        let names = fake_transport(&mut handler, 1, ErrorKind::NotFound);
+        let e = process(&mut handler, &REQUEST_IDENTITIES)
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::NotFound);
+        assert_eq!(names.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_scrub_view_on_drop() {
+        let mut handler = Handler::new(None).await.unwrap();
+        fake_transport(&mut handler, 0, ErrorKind::ConnectionRefused);
+        process(&mut handler, &REQUEST_IDENTITIES).await.unwrap();
+        // Holding the mapping keeps its content after handler unmaps the view.
+        let mut view = AgentView::open(&handler.name).unwrap();
+        assert_eq!(view.bytes()[..5], REQUEST_IDENTITIES);
+        drop(handler);
+        assert!(view.bytes().iter().all(|b| *b == 0));
+    }
+
+    #[tokio::test]
+    async fn test_scrub_view_on_remap() {
+        let mut handler = Handler::new(None).await.unwrap();
+        let stale = Arc::new(Mutex::new(None));
+        let held = stale.clone();
+        // Rejects the first mapping but keeps it open, as a stale agent would.
+        handler.transport = Arc::new(move |name: &str| {
+            let mut held = held.lock().unwrap();
+            if held.is_none() {
+                *held = Some(AgentView::open(name)?);
+                return Err(Error::new(ErrorKind::ConnectionRefused, "fake failure"));
+            }
+            Ok(())
+        });
+        let resp = process(&mut handler, &REQUEST_IDENTITIES).await.unwrap();
+        assert_eq!(resp, REQUEST_IDENTITIES);
+        let mut view = stale.lock().unwrap().take().unwrap();
+        assert!(view.bytes().iter().all(|b| *b == 0));
+    }
+
+    /// Appends an ssh string of `data` to `buf`.
+    fn put_string(buf: &mut Vec<u8>, data: &[u8]) {
+        buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        buf.extend_from_slice(data);
+    }
+
+    #[tokio::test]
+    async fn test_add_identity_with_constraints() {
+        const SSH2_AGENTC_ADD_ID_CONSTRAINED: u8 = 25;
+        const SSH_AGENT_CONSTRAIN_LIFETIME: u8 = 1;
+        const SSH_AGENT_CONSTRAIN_CONFIRM: u8 = 2;
+        // An RSA 8192 key, n, e, d, iqmp, p and q, with lifetime and confirmation.
+        let mut msg = vec![SSH2_AGENTC_ADD_ID_CONSTRAINED];
+        put_string(&mut msg, b"ssh-rsa");
+        for len in [1025, 3, 1025, 513, 513, 513] {
+            put_string(&mut msg, &vec![0x5a; len]);
+        }
+        put_string(&mut msg, b"user@host");
+        msg.push(SSH_AGENT_CONSTRAIN_LIFETIME);
+        msg.extend_from_slice(&3600u32.to_be_bytes());
+        msg.push(SSH_AGENT_CONSTRAIN_CONFIRM);
+        let mut req = vec![];
+        put_string(&mut req, &msg);
+        assert!(req.len() < PUTTY_IPC_MAXLEN);
+
+        let mut handler = Handler::new(None).await.unwrap();
+        let seen = Arc::new(Mutex::new(vec![]));
+        let (agent_seen, len) = (seen.clone(), req.len());
+        handler.transport = Arc::new(move |name: &str| {
+            with_view(name, |view| {
+                *agent_seen.lock().unwrap() = view[..len].to_vec();
+                view[..5].copy_from_slice(&SSH_AGENT_SUCCESS_FRAME);
+            })
+        });
+        let resp = process(&mut handler, &req).await.unwrap();
+        assert_eq!(resp, SSH_AGENT_SUCCESS_FRAME);
+        // Agent gets the request intact, constraints included.
+        assert_eq!(*seen.lock().unwrap(), req);
+    }
+
+    #[tokio::test]
+    async fn test_response_too_large() {
+        let mut handler = Handler::new(None).await.unwrap();
+        // The frame is 4 bytes larger than the declared length.
+        let len = (PUTTY_IPC_MAXLEN as u32 - 3).to_be_bytes();
+        handler.transport =
+            Arc::new(move |name: &str| with_view(name, |view| view[..4].copy_from_slice(&len)));
+        let e = process(&mut handler, &REQUEST_IDENTITIES)
+            .await
+            .unwrap_err();
+        assert!(FrameTooLarge::is(&e));
+    }
+
+    #[tokio::test]
+    async fn test_release_permit_on_timeout() {
+        let stuck = Arc::new(AtomicBool::new(true));
+        let mut names = vec![];
+        for _ in 0..SSH_CONCURRENCY {
+            let mut handler = Handler::new(Some(Duration::from_millis(10))).await.unwrap();
+            let stuck = stuck.clone();
+            handler.transport = Arc::new(move |_: &str| {
+                while stuck.load(Ordering::Relaxed) {
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                Ok(())
+            });
+            let e = process(&mut handler, &REQUEST_IDENTITIES)
+                .await
+                .unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::TimedOut);
+            names.push(handler.name.clone());
+        }
+        // Requests stuck in agent don't hold permits, but keep their names.
+        let handler = time::timeout(Duration::from_secs(5), Handler::new(None))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!names.contains(&handler.name));
+        stuck.store(false, Ordering::Relaxed);
+    }
+
+    #[tokio::test]
+    async fn test_frame_limit() {
+        let limit = PUTTY_IPC_MAXLEN;
+        for len in [limit - 1, limit, limit + 1] {
+            let mut handler = Handler::new(None).await.unwrap();
+            fake_transport(&mut handler, 0, ErrorKind::ConnectionRefused);
+            let mut req = vec![0x5a; len];
+            req[..4].copy_from_slice(&(len as u32 - 4).to_be_bytes());
+            let res = process(&mut handler, &req).await;
+            if len <= limit {
+                // The whole frame, length prefix included, fits in the view.
+                assert_eq!(res.unwrap(), req);
+            } else {
+                assert!(FrameTooLarge::is(&res.unwrap_err()));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_guid_map_names() {
+        let guid = random_guid().unwrap();
+        let groups: Vec<_> = guid.split('-').map(str::len).collect();
+        assert_eq!(groups, [8, 4, 4, 4, 12]);
+        assert_eq!(&guid[14..15], "4");
+
+        // Other tests don't depend on how mappings are named.
+        set_guid_map_names(true);
+        let mut names = std::collections::HashSet::new();
+        for _ in 0..200 {
+            // Handlers are dropped right away, so they would reuse the same token.
+            let handler = Handler::new(None).await.unwrap();
+            assert!(handler.name.ends_with('\0'));
+            assert_eq!(handler.name.len(), 48);
+            assert!(names.insert(handler.name.clone()), "{}", handler.name);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_byte_counts() {
+        // No identities, 4 bytes prefix, 1 byte type and 4 bytes count.
+        const ANSWER: [u8; 9] = [0, 0, 0, 5, SSH_AGENT_IDENTITIES_ANSWER, 0, 0, 0, 0];
+        let mut handler = Handler::new(None).await.unwrap();
+        handler.transport =
+            Arc::new(|name: &str| with_view(name, |view| view[..9].copy_from_slice(&ANSWER)));
+        for _ in 0..2 {
+            let resp = process(&mut handler, &REQUEST_IDENTITIES).await.unwrap();
+            assert_eq!(resp, ANSWER);
+        }
+        assert_eq!(handler.received(), 2 * REQUEST_IDENTITIES.len());
+        assert_eq!(handler.replied(), 2 * ANSWER.len());
+        // A truncated request is not counted.
+        let mut reader: Pin<Box<dyn AsyncRead + Send + '_>> = Box::pin(&[0, 0, 0, 5, 11][..]);
+        assert!(handler.process_one(&mut reader).await.is_err());
+        assert_eq!(handler.received(), 2 * REQUEST_IDENTITIES.len());
+        assert_eq!(handler.replied(), 2 * ANSWER.len());
+    }
+
+    #[test]
+    fn test_parse_identities() {
+        let mut key = vec![];
+        put_string(&mut key, b"ssh-ed25519");
+        put_string(&mut key, &[0x5a; 32]);
+        let mut msg = vec![SSH_AGENT_IDENTITIES_ANSWER];
+        msg.extend_from_slice(&1u32.to_be_bytes());
+        put_string(&mut msg, &key);
+        put_string(&mut msg, b"user@host");
+        let mut resp = vec![];
+        put_string(&mut resp, &msg);
+
+        let identities = parse_identities(&resp).unwrap();
+        assert_eq!(identities.len(), 1);
+        assert_eq!(identities[0].key_type, "ssh-ed25519");
+        assert_eq!(
+            identities[0].fingerprint,
+            "SHA256:jysidH8sUN9+mB/5Wx25m7gT4aa66R2kbn4o2cy0Vio"
+        );
+        assert_eq!(identities[0].comment, "user@host");
+
+        let e = parse_identities(&resp[..resp.len() - 1]).err().unwrap();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        assert!(parse_identities(&SSH_AGENT_FAILURE_FRAME).is_err());
+    }
+}
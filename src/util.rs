@@ -1,36 +1,144 @@
 use std::{
-    io, mem,
+    collections::HashMap,
+    env,
+    ffi::c_void,
+    fs,
+    io::{self, Write},
+    mem,
+    net::SocketAddr,
+    os::windows::{
+        fs::MetadataExt,
+        io::{AsRawHandle, AsRawSocket, FromRawSocket, IntoRawSocket, OwnedSocket, RawSocket},
+    },
+    path::{Path, PathBuf},
     pin::Pin,
+    ptr, slice,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Once, OnceLock,
+    },
     task::{Context, Poll},
+    thread,
 };
 
-use futures::{ready, Future};
-use log::trace;
+use futures::{ready, stream, Future, Stream};
+use log::{error, info, trace, warn};
 use tokio::{
     io::{AsyncRead, AsyncWrite, ReadBuf},
     net::{
+        lookup_host,
         windows::named_pipe::{NamedPipeServer, ServerOptions},
-        TcpListener, TcpStream,
+        TcpListener, TcpSocket, TcpStream,
     },
+    sync::mpsc,
+    task::JoinHandle,
+};
+use windows::core::{GUID, PCWSTR, PWSTR};
+use windows::Win32::Foundation::{
+    CloseHandle, LocalFree, SetHandleInformation, HANDLE, HANDLE_FLAGS, HANDLE_FLAG_INHERIT,
+    HLOCAL, PSID,
+};
+use windows::Win32::Networking::WinSock::{
+    self, closesocket, listen, WSAGetLastError, WSASocketW, WSAStartup, ADDRESS_FAMILY, AF_UNIX,
+    INVALID_SOCKET, SOCKADDR, SOCKADDR_UN, SOCKET, SOCK_STREAM, WSADATA, WSAECONNREFUSED,
+    WSA_FLAG_NO_HANDLE_INHERIT, WSA_FLAG_OVERLAPPED,
+};
+use windows::Win32::Security::Authorization::{
+    ConvertSecurityDescriptorToStringSecurityDescriptorW, ConvertSidToStringSidW,
+    ConvertStringSecurityDescriptorToSecurityDescriptorW, GetSecurityInfo, SDDL_REVISION_1,
+    SE_KERNEL_OBJECT,
 };
+use windows::Win32::Security::{
+    EqualSid, GetTokenInformation, RevertToSelf, TokenGroups, TokenUser, DACL_SECURITY_INFORMATION,
+    GROUP_SECURITY_INFORMATION, OWNER_SECURITY_INFORMATION, PSECURITY_DESCRIPTOR,
+    SECURITY_ATTRIBUTES, TOKEN_GROUPS, TOKEN_INFORMATION_CLASS, TOKEN_QUERY, TOKEN_USER,
+};
+use windows::Win32::System::Pipes::{GetNamedPipeClientProcessId, ImpersonateNamedPipeClient};
+use windows::Win32::System::Threading::{
+    GetCurrentProcess, GetCurrentThread, OpenProcessToken, OpenThreadToken,
+};
+
+static TRACE_TIMING: AtomicBool = AtomicBool::new(false);
+
+/// Logs durations of every phase of forwarding at debug level if `enabled`.
+pub fn set_trace_timing(enabled: bool) {
+    TRACE_TIMING.store(enabled, Ordering::Relaxed);
+}
+
+pub(crate) fn trace_timing() -> bool {
+    TRACE_TIMING.load(Ordering::Relaxed)
+}
+
+/// Default of `set_max_message_log_bytes`.
+pub const DEFAULT_MAX_MESSAGE_LOG_BYTES: usize = 256;
+
+static MAX_MESSAGE_LOG_BYTES: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_MESSAGE_LOG_BYTES);
+
+/// Only logs the first `max` bytes of every message at trace level.
+pub fn set_max_message_log_bytes(max: usize) {
+    MAX_MESSAGE_LOG_BYTES.store(max, Ordering::Relaxed);
+}
+
+/// Formats `data` for trace logs, truncated to the limit of `set_max_message_log_bytes`.
+pub(crate) fn loggable(data: &[u8]) -> String {
+    let max = MAX_MESSAGE_LOG_BYTES.load(Ordering::Relaxed);
+    if data.len() <= max {
+        return format!("{:?}", String::from_utf8_lossy(data));
+    }
+    format!(
+        "{:?}...(truncated, total {} bytes)",
+        String::from_utf8_lossy(&data[..max]),
+        data.len()
+    )
+}
 
 pub fn other_error(details: String) -> io::Error {
-    io::Error::new(io::ErrorKind::Other, details)
+    io::Error::other(details)
 }
 
 pub type PinAsyncRead<'a> = Pin<Box<dyn AsyncRead + Send + 'a>>;
 pub type PinAsyncWrite<'a> = Pin<Box<dyn AsyncWrite + Send + 'a>>;
 
+/// Who is on the other end of a connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Peer {
+    Tcp(SocketAddr),
+    /// Process id of the Named Pipe client.
+    Pipe(u32),
+    Unknown,
+}
+
 pub trait SplitStream {
-    fn split_rw(&mut self) -> (PinAsyncRead, PinAsyncWrite);
+    fn split_rw(&mut self) -> (PinAsyncRead<'_>, PinAsyncWrite<'_>);
+
+    fn peer(&self) -> Peer {
+        Peer::Unknown
+    }
 }
 
 impl SplitStream for TcpStream {
     #[inline]
-    fn split_rw(&mut self) -> (PinAsyncRead, PinAsyncWrite) {
+    fn split_rw(&mut self) -> (PinAsyncRead<'_>, PinAsyncWrite<'_>) {
         let (read_half, write_half) = TcpStream::split(self);
         (Box::pin(read_half), Box::pin(write_half))
     }
+
+    fn peer(&self) -> Peer {
+        match self.peer_addr() {
+            Ok(addr) => Peer::Tcp(addr),
+            Err(_) => Peer::Unknown,
+        }
+    }
+}
+
+/// In-memory stream, which is useful to drive both ends in the same process.
+#[cfg(test)]
+impl SplitStream for tokio::io::DuplexStream {
+    #[inline]
+    fn split_rw(&mut self) -> (PinAsyncRead<'_>, PinAsyncWrite<'_>) {
+        let (read_half, write_half) = tokio::io::split(self);
+        (Box::pin(read_half), Box::pin(write_half))
+    }
 }
 
 struct PipeServerRead<'a> {
@@ -83,7 +191,7 @@ impl<'a> AsyncWrite for PipeServerWrite<'a> {
             match self.server.try_write(buf) {
                 Ok(n) => return Poll::Ready(Ok(n)),
                 Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    if let Err(e) = ready!(self.server.poll_read_ready(cx)) {
+                    if let Err(e) = ready!(self.server.poll_write_ready(cx)) {
                         return Poll::Ready(Err(e));
                     }
                 }
@@ -104,25 +212,45 @@ impl<'a> AsyncWrite for PipeServerWrite<'a> {
 }
 
 impl SplitStream for NamedPipeServer {
-    fn split_rw(&mut self) -> (PinAsyncRead, PinAsyncWrite) {
+    fn split_rw(&mut self) -> (PinAsyncRead<'_>, PinAsyncWrite<'_>) {
         (
             Box::pin(PipeServerRead { server: self }),
             Box::pin(PipeServerWrite { server: self }),
         )
     }
+
+    fn peer(&self) -> Peer {
+        let mut pid = 0;
+        let pipe = HANDLE(self.as_raw_handle() as isize);
+        match unsafe { GetNamedPipeClientProcessId(pipe, &mut pid) } {
+            Ok(()) => Peer::Pipe(pid),
+            Err(_) => Peer::Unknown,
+        }
+    }
 }
 
+impl<T: SplitStream + ?Sized> SplitStream for Box<T> {
+    #[inline]
+    fn split_rw(&mut self) -> (PinAsyncRead<'_>, PinAsyncWrite<'_>) {
+        (**self).split_rw()
+    }
+
+    #[inline]
+    fn peer(&self) -> Peer {
+        (**self).peer()
+    }
+}
+
+pub type AcceptFuture<'a, C> = Pin<Box<dyn Future<Output = io::Result<C>> + Send + 'a>>;
+
 pub trait Listener {
     type Connection;
-    fn accept<'a>(&'a mut self)
-        -> Pin<Box<dyn Future<Output = io::Result<Self::Connection>> + 'a>>;
+    fn accept(&mut self) -> AcceptFuture<'_, Self::Connection>;
 }
 
 impl Listener for TcpListener {
     type Connection = TcpStream;
-    fn accept<'a>(
-        &'a mut self,
-    ) -> Pin<Box<dyn Future<Output = io::Result<Self::Connection>> + 'a>> {
+    fn accept(&mut self) -> AcceptFuture<'_, Self::Connection> {
         Box::pin(async move {
             let (conn, _) = TcpListener::accept(self).await?;
             Ok(conn)
@@ -130,26 +258,1262 @@ impl Listener for TcpListener {
     }
 }
 
+/// Accepts clients of a Named Pipe in background.
+///
+/// A pipe instance can only serve one client, a new instance has to be created after a client
+/// is connected. Connected instances are queued, so new instances can be created without
+/// waiting for previous clients to be handled.
 pub struct NamedPipeServerListener {
-    server: NamedPipeServer,
-    addr: String,
+    queue: mpsc::Receiver<io::Result<NamedPipeServer>>,
+    task: JoinHandle<()>,
 }
 
 impl NamedPipeServerListener {
-    pub fn new(server: NamedPipeServer, addr: String) -> NamedPipeServerListener {
-        NamedPipeServerListener { server, addr }
+    /// `queue_depth` is the maximum number of connected clients waiting to be accepted.
+    /// `options` and `security` are used to create new instances after `server`. If
+    /// `require_same_user` is true, clients not running as the same user as current process
+    /// are disconnected.
+    pub fn new(
+        server: NamedPipeServer,
+        addr: String,
+        options: ServerOptions,
+        security: Option<Arc<PipeSecurity>>,
+        queue_depth: usize,
+        require_same_user: bool,
+    ) -> NamedPipeServerListener {
+        let (tx, queue) = mpsc::channel(queue_depth.max(1));
+        let task = tokio::spawn(accept_pipe_clients(
+            server,
+            addr,
+            options,
+            security,
+            tx,
+            require_same_user,
+        ));
+        NamedPipeServerListener { queue, task }
+    }
+}
+
+async fn accept_pipe_clients(
+    mut server: NamedPipeServer,
+    addr: String,
+    mut options: ServerOptions,
+    security: Option<Arc<PipeSecurity>>,
+    tx: mpsc::Sender<io::Result<NamedPipeServer>>,
+    require_same_user: bool,
+) {
+    options.first_pipe_instance(false);
+    loop {
+        if let Err(e) = server.connect().await {
+            let _ = tx.send(Err(e)).await;
+            return;
+        }
+        if require_same_user {
+            let reason = match is_same_user(&server) {
+                Ok(true) => None,
+                Ok(false) => Some("client runs as another user".to_string()),
+                Err(e) => Some(format!("failed to check client user: {}", e)),
+            };
+            if let Some(reason) = reason {
+                warn!("reject client of {}: {}", addr, reason);
+                if let Err(e) = server.disconnect() {
+                    let _ = tx.send(Err(e)).await;
+                    return;
+                }
+                continue;
+            }
+        }
+        // The connected instance is handed out, so a new instance is created to accept
+        // further clients. It's never the first instance, it joins the pipe created by
+        // `bind` or by whoever created it before `bind`.
+        let next = match create_pipe(&options, security.as_deref(), &addr) {
+            Ok(s) => s,
+            Err(e) => {
+                let _ = tx.send(Err(e)).await;
+                return;
+            }
+        };
+        if tx.send(Ok(mem::replace(&mut server, next))).await.is_err() {
+            return;
+        }
+    }
+}
+
+impl Drop for NamedPipeServerListener {
+    fn drop(&mut self) {
+        self.task.abort();
     }
 }
 
 impl Listener for NamedPipeServerListener {
     type Connection = NamedPipeServer;
-    fn accept<'a>(
-        &'a mut self,
-    ) -> Pin<Box<dyn Future<Output = io::Result<Self::Connection>> + 'a>> {
+    fn accept(&mut self) -> AcceptFuture<'_, Self::Connection> {
+        Box::pin(async move {
+            match self.queue.recv().await {
+                Some(res) => res,
+                None => Err(other_error("pipe listener is closed".to_string())),
+            }
+        })
+    }
+}
+
+/// Closes the token handle on drop.
+struct Token(HANDLE);
+
+impl Drop for Token {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = CloseHandle(self.0);
+        }
+    }
+}
+
+impl Token {
+    fn of_current_process() -> io::Result<Token> {
+        let mut handle = HANDLE::default();
+        unsafe { OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut handle) }
+            .map_err(|e| other_error(format!("failed to open process token: {:?}", e)))?;
+        Ok(Token(handle))
+    }
+
+    /// Gets the token of the client connected to `server`.
+    ///
+    /// Current thread impersonates the client only to open the token, and reverts before
+    /// returning.
+    fn of_pipe_client(server: &NamedPipeServer) -> io::Result<Token> {
+        let pipe = HANDLE(server.as_raw_handle() as isize);
+        unsafe { ImpersonateNamedPipeClient(pipe) }
+            .map_err(|e| other_error(format!("failed to impersonate client: {:?}", e)))?;
+        let mut handle = HANDLE::default();
+        // Opens as self, the client may not be allowed to query its own token.
+        let res = unsafe { OpenThreadToken(GetCurrentThread(), TOKEN_QUERY, true, &mut handle) };
+        if let Err(e) = unsafe { RevertToSelf() } {
+            // The thread is shared by other tasks, they must not run as the client.
+            error!("failed to revert impersonation: {:?}", e);
+            std::process::abort();
+        }
+        res.map_err(|e| other_error(format!("failed to open client token: {:?}", e)))?;
+        Ok(Token(handle))
+    }
+
+    /// Reads information `class` of the token into `buf`.
+    fn query(&self, class: TOKEN_INFORMATION_CLASS, buf: &mut Vec<u64>) -> io::Result<()> {
+        let mut len = 0;
+        // Fails with ERROR_INSUFFICIENT_BUFFER, but returns the required size.
+        let _ = unsafe { GetTokenInformation(self.0, class, None, 0, &mut len) };
+        // Uses u64 to keep the structs aligned.
+        buf.resize((len as usize).div_ceil(mem::size_of::<u64>()), 0);
+        unsafe {
+            GetTokenInformation(
+                self.0,
+                class,
+                Some(buf.as_mut_ptr() as *mut c_void),
+                len,
+                &mut len,
+            )
+        }
+        .map_err(|e| other_error(format!("failed to query token {:?}: {:?}", class, e)))
+    }
+
+    /// Gets the user SID, `buf` holds the data the SID points to.
+    fn user_sid(&self, buf: &mut Vec<u64>) -> io::Result<PSID> {
+        self.query(TokenUser, buf)?;
+        Ok(unsafe { (*(buf.as_ptr() as *const TOKEN_USER)).User.Sid })
+    }
+
+    /// Gets the logon SID, which identifies the logon session, `buf` holds the data the SID
+    /// points to.
+    fn logon_sid(&self, buf: &mut Vec<u64>) -> io::Result<PSID> {
+        self.query(TokenGroups, buf)?;
+        let groups = unsafe {
+            let groups = &*(buf.as_ptr() as *const TOKEN_GROUPS);
+            slice::from_raw_parts(groups.Groups.as_ptr(), groups.GroupCount as usize)
+        };
+        groups
+            .iter()
+            .find(|g| g.Attributes & SE_GROUP_LOGON_ID == SE_GROUP_LOGON_ID)
+            .map(|g| g.Sid)
+            .ok_or_else(|| other_error("token has no logon sid".to_string()))
+    }
+}
+
+/// `SE_GROUP_LOGON_ID`, marks the logon SID in token groups.
+const SE_GROUP_LOGON_ID: u32 = 0xC000_0000;
+
+/// A security descriptor for Named Pipes that only grants the current user, or the current
+/// logon session, access.
+///
+/// Without it, a pipe gets the default DACL, which lets everyone open it for reading, and
+/// lets administrators and LocalSystem open it for writing, even when they are in other
+/// sessions.
+pub struct PipeSecurity(PSECURITY_DESCRIPTOR);
+
+// The descriptor is never modified after it's created.
+unsafe impl Send for PipeSecurity {}
+unsafe impl Sync for PipeSecurity {}
+
+impl PipeSecurity {
+    pub fn owner_only() -> io::Result<PipeSecurity> {
+        let token = Token::of_current_process()?;
+        let mut buf = vec![];
+        PipeSecurity::granted_to(token.user_sid(&mut buf)?)
+    }
+
+    /// Grants only processes in the current logon session access, so the same user logged on
+    /// in other sessions, like another RDP session, can't open the pipe either.
+    pub fn session_only() -> io::Result<PipeSecurity> {
+        let token = Token::of_current_process()?;
+        let mut buf = vec![];
+        PipeSecurity::granted_to(token.logon_sid(&mut buf)?)
+    }
+
+    fn granted_to(sid: PSID) -> io::Result<PipeSecurity> {
+        let mut sid_str = PWSTR::null();
+        unsafe { ConvertSidToStringSidW(sid, &mut sid_str) }
+            .map_err(|e| other_error(format!("failed to convert sid: {:?}", e)))?;
+        let sid = unsafe { sid_str.to_string() };
+        unsafe {
+            let _ = LocalFree(HLOCAL(sid_str.0 as *mut c_void));
+        }
+        let sid = sid.map_err(|e| other_error(format!("invalid sid: {}", e)))?;
+        // A protected DACL, so nothing is inherited from the pipe file system.
+        let sddl: Vec<u16> = format!("D:P(A;;GA;;;{})", sid)
+            .encode_utf16()
+            .chain(Some(0))
+            .collect();
+        let mut sd = PSECURITY_DESCRIPTOR::default();
+        unsafe {
+            ConvertStringSecurityDescriptorToSecurityDescriptorW(
+                PCWSTR::from_raw(sddl.as_ptr()),
+                SDDL_REVISION_1,
+                &mut sd,
+                None,
+            )
+        }
+        .map_err(|e| other_error(format!("failed to create security descriptor: {:?}", e)))?;
+        Ok(PipeSecurity(sd))
+    }
+}
+
+impl Drop for PipeSecurity {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = LocalFree(HLOCAL(self.0 .0));
+        }
+    }
+}
+
+/// Creates an instance of pipe `addr`, protected by `security` if specified.
+fn create_pipe(
+    options: &ServerOptions,
+    security: Option<&PipeSecurity>,
+    addr: &str,
+) -> io::Result<NamedPipeServer> {
+    let security = match security {
+        Some(s) => s,
+        None => return options.create(addr),
+    };
+    let mut attrs = SECURITY_ATTRIBUTES {
+        nLength: mem::size_of::<SECURITY_ATTRIBUTES>() as u32,
+        lpSecurityDescriptor: security.0 .0,
+        bInheritHandle: false.into(),
+    };
+    unsafe {
+        options.create_with_security_attributes_raw(addr, &mut attrs as *mut _ as *mut c_void)
+    }
+}
+
+/// Checks whether the client connected to `server` runs as the same user as current process.
+fn is_same_user(server: &NamedPipeServer) -> io::Result<bool> {
+    let client = Token::of_pipe_client(server)?;
+    let current = Token::of_current_process()?;
+    let (mut client_buf, mut current_buf) = (vec![], vec![]);
+    let client_sid = client.user_sid(&mut client_buf)?;
+    let current_sid = current.user_sid(&mut current_buf)?;
+    Ok(unsafe { EqualSid(client_sid, current_sid) }.is_ok())
+}
+
+/// A listener that can be either TCP or Named Pipe.
+pub enum AnyListener {
+    Tcp(TcpListener),
+    Pipe(NamedPipeServerListener),
+    Unix(UnixListener),
+}
+
+impl AnyListener {
+    /// Gets the socket of TCP or Unix domain socket listener, which can be inherited by child
+    /// processes.
+    pub fn raw_socket(&self) -> Option<RawSocket> {
+        match self {
+            AnyListener::Tcp(l) => Some(l.as_raw_socket()),
+            AnyListener::Pipe(_) => None,
+            AnyListener::Unix(l) => Some(l.socket),
+        }
+    }
+
+    /// Turns the listener into a stream of accepted connections, so it can be polled in
+    /// custom loops.
+    pub fn incoming(self) -> impl Stream<Item = io::Result<Box<dyn SplitStream + Send>>> {
+        stream::unfold(self, |mut listener| async move {
+            let conn = Listener::accept(&mut listener).await;
+            Some((conn, listener))
+        })
+    }
+}
+
+impl Listener for AnyListener {
+    type Connection = Box<dyn SplitStream + Send>;
+    fn accept(&mut self) -> AcceptFuture<'_, Self::Connection> {
+        Box::pin(async move {
+            let conn: Self::Connection = match self {
+                AnyListener::Tcp(l) => Box::new(Listener::accept(l).await?),
+                AnyListener::Pipe(l) => Box::new(l.accept().await?),
+                AnyListener::Unix(l) => Box::new(l.accept().await?),
+            };
+            Ok(conn)
+        })
+    }
+}
+
+/// Expands environment variables in the form of `%VAR%` and `${VAR}` in `s`.
+///
+/// `%%` stands for a literal `%`, and a `%` without a closing one is kept as is. Undefined
+/// variables are errors, so a typo doesn't end up as a path.
+pub fn expand_env_vars(s: &str) -> io::Result<String> {
+    expand_env_vars_with(s, |name| env::var(name).ok())
+}
+
+/// Same as `expand_env_vars`, but looks up variables by `lookup`.
+fn expand_env_vars_with(s: &str, lookup: impl Fn(&str) -> Option<String>) -> io::Result<String> {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(pos) = rest.find(['%', '$']) {
+        out.push_str(&rest[..pos]);
+        let tail = &rest[pos..];
+        if let Some(t) = tail.strip_prefix("%%") {
+            out.push('%');
+            rest = t;
+            continue;
+        }
+        let (name, len) = if let Some(t) = tail.strip_prefix('%') {
+            match t.find('%') {
+                Some(end) => (&t[..end], end + 2),
+                None => ("", 1),
+            }
+        } else if let Some(t) = tail.strip_prefix("${") {
+            match t.find('}') {
+                Some(end) if end > 0 => (&t[..end], end + 3),
+                _ => ("", 1),
+            }
+        } else {
+            ("", 1)
+        };
+        if name.is_empty() {
+            out.push_str(&tail[..len]);
+        } else {
+            match lookup(name) {
+                Some(value) => out.push_str(&value),
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("environment variable {} is not defined in {}", name, s),
+                    ))
+                }
+            }
+        }
+        rest = &tail[len..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Checks if `addr` should be treated as Named Pipe.
+pub fn is_pipe_addr(addr: &str) -> bool {
+    // We can also try to guess ':'. But then we can distinguish between named pipe localhost and
+    // invalid tcp address localhost. Force check '\pipe\' can allow those address fail with clear
+    // error.
+    addr.starts_with(PIPE_PREFIX)
+}
+
+const PIPE_PREFIX: &str = "\\\\.\\pipe\\";
+const LOCAL_PIPE_PREFIX: &str = "\\\\.\\pipe\\Local\\";
+/// Maximum length of a pipe name, including the `\\.\pipe\` prefix.
+const MAX_PIPE_NAME_LEN: usize = 256;
+
+/// Moves Named Pipe `addr` under `\\.\pipe\Local\`.
+///
+/// Unlike other kernel objects, named pipes have no per session namespace, so `Local\` is only a
+/// part of the name. Isolation comes from binding it with `BindOptions::pipe_session_only`.
+/// Clients need to connect to the returned name instead of `addr`.
+pub fn local_pipe_addr(addr: &str) -> io::Result<String> {
+    let name = match addr.strip_prefix(PIPE_PREFIX) {
+        Some(name) => name,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a named pipe", addr),
+            ))
+        }
+    };
+    let local = if addr.starts_with(LOCAL_PIPE_PREFIX) {
+        addr.to_owned()
+    } else {
+        format!("{}{}", LOCAL_PIPE_PREFIX, name)
+    };
+    if local.len() == LOCAL_PIPE_PREFIX.len() || local.len() > MAX_PIPE_NAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid pipe name {}", local),
+        ));
+    }
+    Ok(local)
+}
+
+/// Checks whether `path` can't be accessed because one of its components is a reparse point,
+/// like a symbolic link or a junction, whose target is unavailable.
+///
+/// It's common in roaming profiles that a junction points to a network drive that is not
+/// mounted, in which case opening `path` only reports a generic error.
+pub fn unavailable_reparse_target(path: &Path) -> Option<io::Error> {
+    for p in path.ancestors() {
+        let meta = match fs::symlink_metadata(p) {
+            Ok(m) => m,
+            Err(_) => continue,
+        };
+        if !meta.file_type().is_symlink() {
+            // Components above an accessible directory are accessible as well.
+            if meta.is_dir() {
+                return None;
+            }
+            continue;
+        }
+        let e = match fs::metadata(p) {
+            Ok(_) => return None,
+            Err(e) => e,
+        };
+        let target = match fs::read_link(p) {
+            Ok(t) => t.display().to_string(),
+            Err(_) => "<unknown>".to_owned(),
+        };
+        return Some(io::Error::new(
+            e.kind(),
+            format!(
+                "{} is behind reparse point {} whose target {} is unavailable: {}",
+                path.display(),
+                p.display(),
+                target,
+                e
+            ),
+        ));
+    }
+    None
+}
+
+/// Default number of connected pipe clients waiting to be accepted.
+pub const DEFAULT_PIPE_QUEUE_DEPTH: usize = 16;
+
+/// Options used by `bind`.
+#[derive(Clone, Debug)]
+pub struct BindOptions {
+    /// If it's false, Named Pipe that has been created by other process can be joined. Clients
+    /// are then dispatched to any of the instances, so it should only be used when the pipe is
+    /// created by a trusted process.
+    pub first_pipe_instance: bool,
+    /// See `NamedPipeServerListener::new`.
+    pub pipe_queue_depth: usize,
+    /// Whether to reject clients connecting from other machines over SMB.
+    pub pipe_reject_remote: bool,
+    /// Whether to reject clients not running as the same user as current process.
+    pub pipe_require_same_user: bool,
+    /// Whether Named Pipes only grant the current user access, see `PipeSecurity`.
+    pub pipe_owner_only: bool,
+    /// Whether Named Pipes only grant the current logon session access, see
+    /// `PipeSecurity::session_only`. It takes precedence over `pipe_owner_only`.
+    pub pipe_session_only: bool,
+    /// Whether TCP listeners use `SO_EXCLUSIVEADDRUSE` instead of `SO_REUSEADDR`, see
+    /// `bind_tcp`.
+    pub tcp_exclusive: bool,
+}
+
+impl Default for BindOptions {
+    fn default() -> BindOptions {
+        BindOptions {
+            first_pipe_instance: true,
+            pipe_queue_depth: DEFAULT_PIPE_QUEUE_DEPTH,
+            pipe_reject_remote: true,
+            pipe_require_same_user: false,
+            pipe_owner_only: false,
+            pipe_session_only: false,
+            tcp_exclusive: false,
+        }
+    }
+}
+
+/// Kind of a listening address.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenKind {
+    NamedPipe(String),
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+    /// Hyper-V socket, identified by the vsock port of its service.
+    Vsock(u32),
+}
+
+/// Classifies `addr` the same way as `bind` without binding it.
+///
+/// TCP address should be an IP address with port. Host names are rejected, as resolving them
+/// may block on DNS, `bind` resolves them instead. Paths ending with `.sock` are Unix domain
+/// sockets, and `vsock:<PORT>` is a Hyper-V socket.
+pub fn parse_listen_addr(addr: &str) -> io::Result<ListenKind> {
+    if is_pipe_addr(addr) {
+        if addr.len() == PIPE_PREFIX.len() || addr.len() > MAX_PIPE_NAME_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid pipe name {}", addr),
+            ));
+        }
+        return Ok(ListenKind::NamedPipe(addr.to_owned()));
+    }
+    if let Ok(addr) = addr.parse() {
+        return Ok(ListenKind::Tcp(addr));
+    }
+    if let Some(port) = addr.strip_prefix(VSOCK_PREFIX) {
+        return match port.parse() {
+            Ok(port) => Ok(ListenKind::Vsock(port)),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid vsock port {}", port),
+            )),
+        };
+    }
+    if is_unix_addr(addr) {
+        // sun_path needs a trailing NUL.
+        if addr.len() >= MAX_UNIX_PATH_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "unix socket path {} is longer than {} bytes",
+                    addr,
+                    MAX_UNIX_PATH_LEN - 1
+                ),
+            ));
+        }
+        return Ok(ListenKind::Unix(PathBuf::from(addr)));
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!(
+            "{} is neither a named pipe, an IP address with port, a vsock port nor a .sock path",
+            addr
+        ),
+    ))
+}
+
+/// Checks if `addr` should be treated as Unix domain socket.
+pub fn is_unix_addr(addr: &str) -> bool {
+    addr.ends_with(".sock")
+}
+
+/// Size of `sun_path`, including the trailing NUL.
+const MAX_UNIX_PATH_LEN: usize = 108;
+
+/// Initializes Winsock, which is only done by std lazily when std sockets are created.
+fn init_winsock() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        let mut data = WSADATA::default();
+        // Initializations are counted, so it's fine to be initialized by std again.
+        let res = unsafe { WSAStartup(0x202, &mut data) };
+        if res != 0 {
+            error!("failed to initialize winsock: {}", res);
+        }
+    });
+}
+
+fn last_socket_error() -> io::Error {
+    io::Error::from_raw_os_error(unsafe { WSAGetLastError() }.0)
+}
+
+/// Creates a stream socket of family `af`, which is not inherited by child processes.
+///
+/// Sockets created by `WinSock::socket` are inheritable, so any process spawned meanwhile,
+/// like gpg-connect-agent, would keep the listener open after it's closed.
+fn stream_socket(af: u16, protocol: i32) -> io::Result<OwnedSocket> {
+    init_winsock();
+    let flags = WSA_FLAG_OVERLAPPED | WSA_FLAG_NO_HANDLE_INHERIT;
+    let socket = unsafe { WSASocketW(af as i32, SOCK_STREAM.0, protocol, None, 0, flags) };
+    if socket == INVALID_SOCKET {
+        return Err(last_socket_error());
+    }
+    Ok(unsafe { OwnedSocket::from_raw_socket(socket.0 as RawSocket) })
+}
+
+/// Creates a Unix domain socket, and gets the address of `path`.
+fn unix_socket(path: &Path) -> io::Result<(OwnedSocket, SOCKADDR_UN)> {
+    let mut addr = SOCKADDR_UN {
+        sun_family: ADDRESS_FAMILY(AF_UNIX),
+        sun_path: [0; MAX_UNIX_PATH_LEN],
+    };
+    // Paths are UTF-8, its length is checked by `parse_listen_addr`.
+    let p = path.to_string_lossy();
+    addr.sun_path[..p.len()].copy_from_slice(p.as_bytes());
+    Ok((stream_socket(AF_UNIX, 0)?, addr))
+}
+
+fn sockaddr_un_ptr(addr: &SOCKADDR_UN) -> (*const SOCKADDR, i32) {
+    (
+        addr as *const SOCKADDR_UN as *const SOCKADDR,
+        mem::size_of::<SOCKADDR_UN>() as i32,
+    )
+}
+
+/// `FILE_ATTRIBUTE_REPARSE_POINT`, Unix domain socket files are reparse points.
+const FILE_ATTRIBUTE_REPARSE_POINT: u32 = 0x400;
+
+/// Checks whether `path` is left by a process that is gone, so nobody accepts connections.
+///
+/// Regular files and directories are never stale sockets, even if connecting to them is
+/// refused.
+fn is_stale_unix_socket(path: &Path) -> io::Result<bool> {
+    let attrs = fs::symlink_metadata(path)?.file_attributes();
+    if attrs & FILE_ATTRIBUTE_REPARSE_POINT == 0 {
+        return Ok(false);
+    }
+    let (socket, addr) = unix_socket(path)?;
+    let (ptr, len) = sockaddr_un_ptr(&addr);
+    if unsafe { WinSock::connect(SOCKET(socket.as_raw_socket() as usize), ptr, len) } == 0 {
+        return Ok(false);
+    }
+    Ok(unsafe { WSAGetLastError() } == WSAECONNREFUSED)
+}
+
+/// Prefix of Hyper-V socket addresses.
+pub const VSOCK_PREFIX: &str = "vsock:";
+
+const AF_HYPERV: u16 = 34;
+const HV_PROTOCOL_RAW: i32 = 1;
+
+/// `SOCKADDR_HV` of hvsocket.h.
+#[repr(C)]
+struct SockaddrHv {
+    family: u16,
+    reserved: u16,
+    vm_id: GUID,
+    service_id: GUID,
+}
+
+/// Listens on Hyper-V socket for vsock `port`, which accepts connections from any VM or
+/// container on this host, like WSL2 or Docker Desktop.
+///
+/// The socket is bound to the wildcard VM id, so every guest on this host, not only the
+/// one the user has in mind, can use the agent through it. Hyper-V sockets carry no user
+/// identity to check either.
+///
+/// Linux guests connect by vsock `port`, which is mapped to service id
+/// `<PORT>-FACB-11E6-BD58-64006A7986D3`. The service needs to be registered under
+/// `HKLM\SOFTWARE\Microsoft\Windows NT\CurrentVersion\Virtualization\GuestCommunicationServices`
+/// before clients can connect.
+fn bind_vsock(port: u32) -> io::Result<UnixListener> {
+    let socket = stream_socket(AF_HYPERV, HV_PROTOCOL_RAW)?;
+    let addr = SockaddrHv {
+        family: AF_HYPERV,
+        reserved: 0,
+        // HV_GUID_WILDCARD.
+        vm_id: GUID::zeroed(),
+        service_id: GUID::from_values(
+            port,
+            0xfacb,
+            0x11e6,
+            [0xbd, 0x58, 0x64, 0x00, 0x6a, 0x79, 0x86, 0xd3],
+        ),
+    };
+    let s = SOCKET(socket.as_raw_socket() as usize);
+    let ptr = &addr as *const SockaddrHv as *const SOCKADDR;
+    if unsafe { WinSock::bind(s, ptr, mem::size_of::<SockaddrHv>() as i32) } != 0 {
+        return Err(last_socket_error());
+    }
+    if unsafe { listen(s, 128) } != 0 {
+        return Err(last_socket_error());
+    }
+    Ok(UnixListener::new(socket.into_raw_socket()))
+}
+
+fn bind_unix(path: &Path) -> io::Result<UnixListener> {
+    let (socket, addr) = unix_socket(path)?;
+    let s = SOCKET(socket.as_raw_socket() as usize);
+    let (ptr, len) = sockaddr_un_ptr(&addr);
+    if unsafe { WinSock::bind(s, ptr, len) } != 0 {
+        let e = last_socket_error();
+        if e.kind() != io::ErrorKind::AddrInUse || !is_stale_unix_socket(path)? {
+            return Err(e);
+        }
+        info!("remove stale unix socket {}", path.display());
+        fs::remove_file(path)?;
+        if unsafe { WinSock::bind(s, ptr, len) } != 0 {
+            return Err(last_socket_error());
+        }
+    }
+    if unsafe { listen(s, 128) } != 0 {
+        return Err(last_socket_error());
+    }
+    Ok(UnixListener::new(socket.into_raw_socket()))
+}
+
+/// Accepts clients of a Unix domain socket or Hyper-V socket in background.
+///
+/// AF_UNIX and AF_HYPERV listeners can't be polled by tokio, so a thread is blocked in
+/// accepting clients instead. Accepted connections can be polled like TCP streams.
+pub struct UnixListener {
+    socket: RawSocket,
+    queue: mpsc::Receiver<io::Result<std::net::TcpStream>>,
+}
+
+impl UnixListener {
+    fn new(socket: RawSocket) -> UnixListener {
+        let (tx, queue) = mpsc::channel(1);
+        thread::spawn(move || accept_unix_clients(socket, tx));
+        UnixListener { socket, queue }
+    }
+}
+
+fn accept_unix_clients(socket: RawSocket, tx: mpsc::Sender<io::Result<std::net::TcpStream>>) {
+    // The socket is closed when the listener is dropped, which fails the pending accept.
+    while !tx.is_closed() {
+        let conn = unsafe { WinSock::accept(SOCKET(socket as usize), None, None) };
+        let res = if conn == INVALID_SOCKET {
+            Err(last_socket_error())
+        } else {
+            let conn = unsafe { std::net::TcpStream::from_raw_socket(conn.0 as RawSocket) };
+            set_inheritable(conn.as_raw_socket(), false)
+                .and_then(|_| conn.set_nonblocking(true))
+                .map(|_| conn)
+        };
+        let failed = res.is_err();
+        if tx.blocking_send(res).is_err() || failed {
+            return;
+        }
+    }
+}
+
+impl Drop for UnixListener {
+    fn drop(&mut self) {
+        unsafe {
+            closesocket(SOCKET(self.socket as usize));
+        }
+    }
+}
+
+impl Listener for UnixListener {
+    type Connection = UnixStream;
+    fn accept(&mut self) -> AcceptFuture<'_, Self::Connection> {
         Box::pin(async move {
-            self.server.connect().await?;
-            let server = ServerOptions::new().create(&self.addr)?;
-            Ok(mem::replace(&mut self.server, server))
+            match self.queue.recv().await {
+                Some(res) => Ok(UnixStream(TcpStream::from_std(res?)?)),
+                None => Err(other_error("unix listener is closed".to_string())),
+            }
         })
     }
 }
+
+/// A connection accepted by `UnixListener`.
+pub struct UnixStream(TcpStream);
+
+impl SplitStream for UnixStream {
+    #[inline]
+    fn split_rw(&mut self) -> (PinAsyncRead<'_>, PinAsyncWrite<'_>) {
+        let (read_half, write_half) = self.0.split();
+        (Box::pin(read_half), Box::pin(write_half))
+    }
+}
+
+/// Environment variable that hands off listeners from an upgrading process to its successor.
+///
+/// Every line is `<ADDRESS> <HANDLE>`. HANDLE is the inherited socket of a TCP or Unix domain
+/// socket listener, or `pipe` for a Named Pipe, which is joined by creating a new instance
+/// instead.
+pub const INHERIT_ENV: &str = "GPG_BRIDGE_INHERIT";
+/// Line written to stdout by the successor after all inherited listeners are bound.
+pub const UPGRADE_READY: &str = "ready";
+
+#[derive(Clone, Copy)]
+enum Inherited {
+    Socket(RawSocket),
+    Pipe,
+}
+
+static INHERITED: OnceLock<parking_lot::Mutex<HashMap<String, Inherited>>> = OnceLock::new();
+
+fn inherited() -> &'static parking_lot::Mutex<HashMap<String, Inherited>> {
+    INHERITED.get_or_init(Default::default)
+}
+
+/// Loads listeners handed off by previous process from `INHERIT_ENV`, returns whether there
+/// is any.
+///
+/// The variable is removed, so children of this process don't inherit the same list.
+/// Changing environment is only safe when no other thread is running, so it should be called
+/// at the start of `main`, before any runtime is built. Listeners are not inherited if it's
+/// never called.
+pub fn load_inherited_listeners() -> bool {
+    let list = match env::var(INHERIT_ENV) {
+        Ok(list) => list,
+        Err(_) => return false,
+    };
+    env::remove_var(INHERIT_ENV);
+    let mut listeners = inherited().lock();
+    for line in list.lines() {
+        let (addr, handle) = match line.rsplit_once(' ') {
+            Some(pair) => pair,
+            None => continue,
+        };
+        let inherited = match handle {
+            "pipe" => Inherited::Pipe,
+            h => match h.parse() {
+                Ok(s) => Inherited::Socket(s),
+                Err(_) => continue,
+            },
+        };
+        listeners.insert(addr.to_owned(), inherited);
+    }
+    !listeners.is_empty()
+}
+
+/// Takes the listener of `addr` handed off by previous process.
+///
+/// Previous process is notified once all its listeners are taken.
+fn take_inherited(addr: &str) -> Option<Inherited> {
+    let mut listeners = inherited().lock();
+    let inherited = listeners.remove(addr)?;
+    if listeners.is_empty() {
+        info!("all inherited listeners are bound");
+        let mut stdout = io::stdout();
+        let _ = writeln!(stdout, "{}", UPGRADE_READY);
+        let _ = stdout.flush();
+    }
+    Some(inherited)
+}
+
+/// Sets whether child processes inherit `socket`.
+pub fn set_inheritable(socket: RawSocket, inheritable: bool) -> io::Result<()> {
+    let flags = match inheritable {
+        true => HANDLE_FLAG_INHERIT,
+        false => HANDLE_FLAGS(0),
+    };
+    let handle = HANDLE(socket as isize);
+    unsafe { SetHandleInformation(handle, HANDLE_FLAG_INHERIT.0, flags) }.map_err(|e| {
+        let msg = format!(
+            "failed to set {} inheritable {}: {:?}",
+            socket, inheritable, e
+        );
+        other_error(msg)
+    })
+}
+
+/// Gets the security descriptor of the pipe `server` in SDDL form.
+fn pipe_sddl(server: &NamedPipeServer) -> io::Result<String> {
+    let pipe = HANDLE(server.as_raw_handle() as isize);
+    let info = OWNER_SECURITY_INFORMATION | GROUP_SECURITY_INFORMATION | DACL_SECURITY_INFORMATION;
+    let mut sd = PSECURITY_DESCRIPTOR::default();
+    unsafe {
+        GetSecurityInfo(
+            pipe,
+            SE_KERNEL_OBJECT,
+            info,
+            None,
+            None,
+            None,
+            None,
+            Some(&mut sd),
+        )
+    }
+    .map_err(|e| other_error(format!("failed to get security info: {:?}", e)))?;
+    let mut sddl = PWSTR::null();
+    let res = unsafe {
+        ConvertSecurityDescriptorToStringSecurityDescriptorW(
+            sd,
+            SDDL_REVISION_1,
+            info,
+            &mut sddl,
+            None,
+        )
+    };
+    unsafe {
+        let _ = LocalFree(HLOCAL(sd.0));
+    }
+    res.map_err(|e| other_error(format!("failed to convert security descriptor: {:?}", e)))?;
+    let s = unsafe { sddl.to_string() };
+    unsafe {
+        let _ = LocalFree(HLOCAL(sddl.0 as *mut c_void));
+    }
+    s.map_err(|e| other_error(format!("invalid security descriptor: {}", e)))
+}
+
+/// Defined as `~SO_REUSEADDR` by ws2def.h, but missing in the bindings.
+const SO_EXCLUSIVEADDRUSE: i32 = !WinSock::SO_REUSEADDR;
+
+/// Binds a TCP listener on `addr`.
+///
+/// By default `SO_REUSEADDR` is set, so a restarted bridge can bind again while connections
+/// of the previous one linger. On Windows it also lets another socket setting `SO_REUSEADDR`
+/// bind the same port and steal connections, so `exclusive` sets `SO_EXCLUSIVEADDRUSE`
+/// instead, which refuses any other socket on the port until it's closed.
+fn bind_tcp(addr: SocketAddr, exclusive: bool) -> io::Result<TcpListener> {
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    if exclusive {
+        let enabled = 1i32.to_ne_bytes();
+        let res = unsafe {
+            WinSock::setsockopt(
+                SOCKET(socket.as_raw_socket() as usize),
+                WinSock::SOL_SOCKET,
+                SO_EXCLUSIVEADDRUSE,
+                Some(&enabled),
+            )
+        };
+        if res != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    } else {
+        socket.set_reuseaddr(true)?;
+    }
+    socket.bind(addr)?;
+    // The same backlog as `TcpListener::bind`.
+    socket.listen(1024)
+}
+
+/// Resolves `addr` in the form of `<HOST>:<PORT>`, returns `e` if it's not in that form.
+async fn resolve_host(addr: &str, e: io::Error) -> io::Result<SocketAddr> {
+    match lookup_host(addr).await {
+        Ok(mut addrs) => addrs.next().ok_or(e),
+        Err(le) if le.kind() == io::ErrorKind::InvalidInput => Err(e),
+        Err(le) => Err(io::Error::new(
+            le.kind(),
+            format!("failed to resolve {}: {}", addr, le),
+        )),
+    }
+}
+
+/// Binds to `addr`, which can be TCP address, Named Pipe or Unix domain socket.
+///
+/// Host names of TCP addresses are resolved and the first address is used, the same way as
+/// `TcpListener::bind`.
+///
+/// Listeners handed off by previous process via `INHERIT_ENV` are reused.
+pub async fn bind(addr: &str, opts: &BindOptions) -> io::Result<AnyListener> {
+    let kind = match parse_listen_addr(addr) {
+        Ok(kind) => kind,
+        Err(e) => ListenKind::Tcp(resolve_host(addr, e).await?),
+    };
+    let inherited = take_inherited(addr);
+    let addr = match kind {
+        ListenKind::NamedPipe(addr) => addr,
+        ListenKind::Unix(path) => {
+            if let Some(Inherited::Socket(socket)) = inherited {
+                return Ok(AnyListener::Unix(UnixListener::new(socket)));
+            }
+            return Ok(AnyListener::Unix(bind_unix(&path)?));
+        }
+        ListenKind::Vsock(port) => {
+            if let Some(Inherited::Socket(socket)) = inherited {
+                return Ok(AnyListener::Unix(UnixListener::new(socket)));
+            }
+            return Ok(AnyListener::Unix(bind_vsock(port)?));
+        }
+        ListenKind::Tcp(addr) => {
+            if let Some(Inherited::Socket(socket)) = inherited {
+                let listener = unsafe { std::net::TcpListener::from_raw_socket(socket) };
+                listener.set_nonblocking(true)?;
+                return Ok(AnyListener::Tcp(TcpListener::from_std(listener)?));
+            }
+            return Ok(AnyListener::Tcp(bind_tcp(addr, opts.tcp_exclusive)?));
+        }
+    };
+    // The pipe is still held by previous process until it's joined.
+    let first_pipe_instance =
+        opts.first_pipe_instance && !matches!(inherited, Some(Inherited::Pipe));
+    let mut options = ServerOptions::new();
+    options.reject_remote_clients(opts.pipe_reject_remote);
+    let security = if opts.pipe_session_only {
+        Some(Arc::new(PipeSecurity::session_only()?))
+    } else if opts.pipe_owner_only {
+        Some(Arc::new(PipeSecurity::owner_only()?))
+    } else {
+        None
+    };
+    let server = create_pipe(
+        options.clone().first_pipe_instance(first_pipe_instance),
+        security.as_deref(),
+        &addr,
+    )?;
+    // Lets admins verify who can connect to the pipe.
+    match pipe_sddl(&server) {
+        Ok(sddl) => info!("{} is protected by {}", addr, sddl),
+        Err(e) => warn!("failed to query security descriptor of {}: {}", addr, e),
+    }
+    Ok(AnyListener::Pipe(NamedPipeServerListener::new(
+        server,
+        addr,
+        options,
+        security,
+        opts.pipe_queue_depth,
+        opts.pipe_require_same_user,
+    )))
+}
+
+/// A buffer that is zeroed when dropped, no matter the copy finishes, fails or is cancelled.
+pub(crate) struct ScrubBuf(pub(crate) Vec<u8>);
+
+impl ScrubBuf {
+    pub(crate) fn new(size: usize) -> ScrubBuf {
+        ScrubBuf(vec![0; size])
+    }
+}
+
+#[cfg(test)]
+thread_local! {
+    /// Whether each `ScrubBuf` dropped on current thread held data, and whether it's zeroed.
+    pub(crate) static SCRUBBED: std::cell::RefCell<Vec<(bool, bool)>> = Default::default();
+}
+
+impl Drop for ScrubBuf {
+    fn drop(&mut self) {
+        #[cfg(test)]
+        let dirty = self.0.iter().any(|b| *b != 0);
+        for b in &mut self.0 {
+            // Volatile write, so it's not optimized away as a dead store before deallocation.
+            unsafe { ptr::write_volatile(b, 0) };
+        }
+        #[cfg(test)]
+        SCRUBBED.with(|s| s.borrow_mut().push((dirty, self.0.iter().all(|b| *b == 0))));
+    }
+}
+
+/// A buffered reader like `BufReader`, but its buffer is a `ScrubBuf`.
+///
+/// Data read ahead, like a private key being added to agent, stays in the buffer until it's
+/// overwritten, so it has to be zeroed when the reader is dropped.
+pub(crate) struct ScrubReader<R> {
+    inner: R,
+    buf: ScrubBuf,
+    pos: usize,
+    filled: usize,
+}
+
+impl<R> ScrubReader<R> {
+    pub(crate) fn with_capacity(capacity: usize, inner: R) -> ScrubReader<R> {
+        ScrubReader {
+            inner,
+            buf: ScrubBuf::new(capacity),
+            pos: 0,
+            filled: 0,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ScrubReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        out: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.pos == this.filled {
+            // Large reads bypass the buffer, like `BufReader`.
+            if out.remaining() >= this.buf.0.len() {
+                return Pin::new(&mut this.inner).poll_read(cx, out);
+            }
+            let mut buf = ReadBuf::new(&mut this.buf.0);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut buf))?;
+            this.filled = buf.filled().len();
+            this.pos = 0;
+        }
+        let n = (this.filled - this.pos).min(out.remaining());
+        out.put_slice(&this.buf.0[this.pos..this.pos + n]);
+        this.pos += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_expand_env_vars() {
+        let lookup = |name: &str| match name {
+            "HOME" => Some(r"C:\Users\me".to_string()),
+            _ => None,
+        };
+        let cases = [
+            (r"%HOME%\gnupg", r"C:\Users\me\gnupg"),
+            (r"${HOME}\gnupg", r"C:\Users\me\gnupg"),
+            (r"%HOME%\${HOME}", r"C:\Users\me\C:\Users\me"),
+            (r"C:\100%\gnupg", r"C:\100%\gnupg"),
+            (r"C:\100%%\%HOME%", r"C:\100%\C:\Users\me"),
+            ("%%", "%"),
+            ("%%HOME%%", "%HOME%"),
+            ("$HOME", "$HOME"),
+            ("${}", "${}"),
+        ];
+        for (s, expanded) in cases {
+            assert_eq!(expand_env_vars_with(s, lookup).unwrap(), expanded, "{}", s);
+        }
+        for s in [r"%UNDEFINED%\gnupg", r"${UNDEFINED}\gnupg", r"C:\100%\50%"] {
+            let e = expand_env_vars_with(s, lookup).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::NotFound, "{}", s);
+        }
+        let e = expand_env_vars_with("%UNDEFINED%", lookup).unwrap_err();
+        assert!(e.to_string().contains("UNDEFINED"), "{}", e);
+    }
+
+    #[tokio::test]
+    async fn test_duplex_echo() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let echo = tokio::spawn(async move {
+            let (mut read, mut write) = server.split_rw();
+            tokio::io::copy(&mut read, &mut write).await
+        });
+        let (mut read, mut write) = client.split_rw();
+        write.write_all(b"hello").await.unwrap();
+        write.shutdown().await.unwrap();
+        let mut echoed = vec![];
+        read.read_to_end(&mut echoed).await.unwrap();
+        assert_eq!(echoed, b"hello");
+        assert_eq!(echo.await.unwrap().unwrap(), 5);
+    }
+
+    /// Counts reads of the inner reader, each of which is a system call on a real stream.
+    struct CountingReader<'a> {
+        data: &'a [u8],
+        reads: usize,
+    }
+
+    impl AsyncRead for CountingReader<'_> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            self.reads += 1;
+            Pin::new(&mut self.data).poll_read(cx, buf)
+        }
+    }
+
+    /// Reads `count` ssh frames the same way `Handler` does, length prefix and body
+    /// separately.
+    async fn read_frames(reader: &mut (impl AsyncRead + Unpin), count: usize) -> Vec<u8> {
+        let mut frames = vec![];
+        for _ in 0..count {
+            let mut len = [0; 4];
+            reader.read_exact(&mut len).await.unwrap();
+            let mut body = vec![0; u32::from_be_bytes(len) as usize];
+            reader.read_exact(&mut body).await.unwrap();
+            frames.extend_from_slice(&len);
+            frames.extend_from_slice(&body);
+        }
+        frames
+    }
+
+    #[tokio::test]
+    async fn test_scrub_reader() {
+        let mut data = vec![];
+        for i in 0..10u8 {
+            data.extend_from_slice(&[0, 0, 0, 100]);
+            data.extend_from_slice(&[i; 100]);
+        }
+
+        let mut direct = CountingReader {
+            data: &data,
+            reads: 0,
+        };
+        assert_eq!(read_frames(&mut direct, 10).await, data);
+        assert_eq!(direct.reads, 20);
+
+        let mut inner = CountingReader {
+            data: &data,
+            reads: 0,
+        };
+        let mut buffered = ScrubReader::with_capacity(8192, &mut inner);
+        assert_eq!(read_frames(&mut buffered, 10).await, data);
+        drop(buffered);
+        assert_eq!(inner.reads, 1);
+
+        // Frames larger than the buffer still pass through.
+        let mut inner = CountingReader {
+            data: &data,
+            reads: 0,
+        };
+        let mut buffered = ScrubReader::with_capacity(16, &mut inner);
+        assert_eq!(read_frames(&mut buffered, 10).await, data);
+    }
+
+    #[tokio::test]
+    async fn test_pipe_session_only() {
+        let addr = format!(r"\\.\pipe\Local\gpg-bridge-test-{}", std::process::id());
+        let security = PipeSecurity::session_only().unwrap();
+        let server = create_pipe(&ServerOptions::new(), Some(&security), &addr).unwrap();
+        let sddl = pipe_sddl(&server).unwrap();
+        let dacl = &sddl[sddl.find("D:").unwrap()..];
+        // Only the logon SID, in the form of S-1-5-5-X-Y, is granted access.
+        assert_eq!(dacl.matches("(A;").count(), 1, "{}", sddl);
+        assert!(dacl.contains(";S-1-5-5-"), "{}", sddl);
+        // Current process is in the session.
+        tokio::net::windows::named_pipe::ClientOptions::new()
+            .open(&addr)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_parse_tcp_addr() {
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 1234));
+        assert_eq!(
+            parse_listen_addr("127.0.0.1:1234").unwrap(),
+            ListenKind::Tcp(addr)
+        );
+        // Host names are left to `bind`.
+        for addr in ["localhost:1234", "localhost", "1234", "127.0.0.1"] {
+            let e = parse_listen_addr(addr).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput, "{}", addr);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bind_host_name() {
+        match bind("localhost:0", &BindOptions::default()).await.unwrap() {
+            AnyListener::Tcp(l) => assert!(l.local_addr().unwrap().ip().is_loopback()),
+            _ => panic!("localhost:0 should be bound as TCP"),
+        }
+        for addr in ["localhost", "localhost:65536"] {
+            let e = bind(addr, &BindOptions::default()).await.err().unwrap();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput, "{}", addr);
+        }
+    }
+
+    #[test]
+    fn test_parse_vsock_addr() {
+        assert_eq!(
+            parse_listen_addr("vsock:4321").unwrap(),
+            ListenKind::Vsock(4321)
+        );
+        assert_eq!(parse_listen_addr("vsock:0").unwrap(), ListenKind::Vsock(0));
+        assert_eq!(
+            parse_listen_addr("vsock:4294967295").unwrap(),
+            ListenKind::Vsock(u32::MAX)
+        );
+        for addr in [
+            "vsock:",
+            "vsock:-1",
+            "vsock:4294967296",
+            "vsock:2:4321",
+            "vsock: 1",
+        ] {
+            let e = parse_listen_addr(addr).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput, "{}", addr);
+        }
+    }
+}